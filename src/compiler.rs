@@ -1,55 +1,2008 @@
-use crate::{lexer::Token, Chunk, Opcode};
+use std::{collections::HashMap, mem};
 
-impl<'a> Token<'a> {
-    fn token_to_opcode(&self, chunk: &mut Chunk) {
+use crate::{
+    diagnostic::Diagnostic,
+    lexer::{Lexer, RloxParseError, ScanError, Token, TokenPos},
+    object::{Capture, Function, Heap, Obj, ObjRef},
+    Index, Opcode, Value,
+};
+
+/// Binding power of operators, from the loosest to the tightest.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    /// One level tighter, used for left associative binary operators.
+    fn next(self) -> Precedence {
         match self {
-            Token::LeftParens => todo!(),
-            Token::RightParens => todo!(),
-            Token::LeftBrace => todo!(),
-            Token::RightBrace => todo!(),
-            Token::LeftSquare => todo!(),
-            Token::RightSquare => todo!(),
-            Token::Comma => todo!(),
-            Token::Dot => todo!(),
-            Token::Minus => todo!(),
-            Token::Plus => todo!(),
-            Token::Semicolon => todo!(),
-            Token::Slash => todo!(),
-            Token::Star => todo!(),
-            Token::Bang => todo!(),
-            Token::BangEqual => todo!(),
-            Token::Equal => todo!(),
-            Token::EqualEqual => todo!(),
-            Token::Greater => todo!(),
-            Token::GreaterEqual => todo!(),
-            Token::Lesser => todo!(),
-            Token::LesserEqual => todo!(),
-            Token::IdentifierNoData => todo!(),
-            Token::String(_) => todo!(),
-            Token::Number(n) => {
-                let idx_in_code = chunk.write_value(*n);
-                chunk.write_opcode(Opcode::Constant(idx_in_code), 42)
-            }
-            Token::And => todo!(),
-            Token::Or => todo!(),
-            Token::Struct => todo!(),
-            Token::If => todo!(),
-            Token::Else => todo!(),
-            Token::True => todo!(),
-            Token::False => todo!(),
-            Token::Fun => todo!(),
-            Token::Loop => todo!(),
-            Token::While => todo!(),
-            Token::For => todo!(),
-            Token::Nil => todo!(),
-            Token::Return => todo!(),
-            Token::Let => todo!(),
-            Token::Print => todo!(),
-            Token::TokSelf => todo!(),
-            Token::SingleComment => todo!(),
-            Token::MultiComment => todo!(),
-            Token::Unknown => todo!(),
-            Token::EOF => todo!(),
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+/// Prefix and infix parsers, the flag tells if an assignment can follow.
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+/// A row of the Pratt table.
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> ParseRule<'a> {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+fn get_rule<'a>(token: &Token<'a>) -> ParseRule<'a> {
+    match token {
+        Token::LeftParens => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        Token::LeftBrace => ParseRule::new(Some(Compiler::map), None, Precedence::None),
+        Token::LeftSquare => ParseRule::new(
+            Some(Compiler::array),
+            Some(Compiler::index),
+            Precedence::Call,
+        ),
+        Token::Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        Token::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        Token::Slash | Token::Star => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Factor)
+        }
+        Token::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        Token::BangEqual | Token::EqualEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
+        }
+        Token::Greater | Token::GreaterEqual | Token::Lesser | Token::LesserEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        Token::Number(_) => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        Token::String(_) => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        Token::Identifier(_) => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        Token::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+        Token::TokSelf => ParseRule::new(Some(Compiler::self_), None, Precedence::None),
+        Token::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+        Token::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+        Token::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+        Token::False | Token::True | Token::Nil => {
+            ParseRule::new(Some(Compiler::literal), None, Precedence::None)
+        }
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+/// A local variable, living in a stack slot.
+#[derive(Debug)]
+struct Local<'a> {
+    name: &'a [u8],
+    // None while its initializer is compiled.
+    depth: Option<usize>,
+    // Closed over by a nested function, moved to the heap when its scope ends.
+    captured: bool,
+}
+
+/// The innermost loop being compiled, target of `break` and `continue`.
+#[derive(Debug)]
+struct Loop {
+    // Where `continue` jumps: the condition, or the increment of a `for`.
+    start: usize,
+    // Locals deeper than this are popped when leaving the loop body.
+    scope_depth: usize,
+    // `break` jumps, patched once the end of the loop is known.
+    breaks: Vec<usize>,
+}
+
+/// Slots are addressed with a u8.
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// Upvalues are addressed with a u8.
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+/// Arguments counts are stored in a u8.
+const MAX_ARGS: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    // The `init` method, returning `self`.
+    Initializer,
+}
+
+/// What is known about the function being compiled.
+struct FunctionState<'a> {
+    function: Function,
+    kind: FunctionKind,
+    // Locals of the enclosing blocks, their index is their stack slot.
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    // Index of the constants already in the chunk, to store each one once.
+    constants: HashMap<ConstantKey, usize>,
+}
+
+/// A constant compared by identity: numbers by their bits as f64 is not Eq,
+/// objects by reference since strings are interned.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Obj(ObjRef),
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: Option<&[u8]>) -> FunctionState<'a> {
+        let mut locals = Vec::with_capacity(MAX_LOCALS);
+        // The slot zero holds the function being called, or `self` for methods.
+        let slot_zero: &[u8] = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => b"self",
+            FunctionKind::Script | FunctionKind::Function => b"",
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: Some(0),
+            captured: false,
+        });
+        FunctionState {
+            function: Function::new(name.map(|name| String::from_utf8_lossy(name).into())),
+            kind,
+            locals,
+            scope_depth: 0,
+            loops: Vec::new(),
+            constants: HashMap::new(),
+        }
+    }
+}
+
+/// A struct declaration being compiled.
+struct StructState {
+    // Its methods can use `super`.
+    has_superclass: bool,
+}
+
+/// Single pass compiler: pull tokens from the lexer and emit opcodes as soon as
+/// an expression is recognized.
+pub struct Compiler<'a> {
+    // Kept to quote the faulty lines in diagnostics.
+    source: &'a str,
+    file: Option<&'a str>,
+    lexer: Lexer<'a>,
+    current: TokenPos<'a>,
+    previous: TokenPos<'a>,
+    state: FunctionState<'a>,
+    // Functions whose compilation is suspended by a nested declaration.
+    enclosing: Vec<FunctionState<'a>>,
+    // Struct declarations being compiled, `self` is only valid inside.
+    structs: Vec<StructState>,
+    // String constants and functions are allocated in the virtual machine heap.
+    heap: &'a mut Heap,
+    // Every error reported, the compilation goes on to find the next ones.
+    errors: Vec<Diagnostic>,
+    // Set after an error until the next statement boundary, to not report its cascade.
+    panic_mode: bool,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(code: &'a str, heap: &'a mut Heap) -> Compiler<'a> {
+        let lexer = Lexer::new(code.as_bytes());
+        Compiler {
+            source: code,
+            file: None,
+            current: lexer.eof(),
+            previous: lexer.eof(),
+            lexer,
+            state: FunctionState::new(FunctionKind::Script, None),
+            enclosing: Vec::new(),
+            structs: Vec::new(),
+            heap,
+            errors: Vec::new(),
+            panic_mode: false,
+        }
+    }
+
+    /// Name the file of the code in diagnostics.
+    pub fn set_file(&mut self, file: &'a str) {
+        self.file = Some(file);
+    }
+
+    /// Compile the whole code as the body of the script function, or return
+    /// all the errors found.
+    pub fn compile(mut self) -> Result<Function, Vec<Diagnostic>> {
+        self.advance();
+        while !self.matches(Token::EOF) {
+            self.declaration();
+        }
+        let script = self.end_function();
+        if self.errors.is_empty() {
+            Ok(script)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Suspend the current function to compile a nested one.
+    fn begin_function(&mut self, kind: FunctionKind, name: &[u8]) {
+        let enclosing = mem::replace(&mut self.state, FunctionState::new(kind, Some(name)));
+        self.enclosing.push(enclosing);
+    }
+
+    /// Resume the enclosing function, returning the one just compiled.
+    fn end_function(&mut self) -> Function {
+        self.emit_return();
+        #[cfg(feature = "debug_print_code")]
+        if self.errors.is_empty() {
+            let function = &self.state.function;
+            let name = function.name.as_deref().unwrap_or("script");
+            println!("{}", function.chunk.dissemble(name, self.heap));
+        }
+        let enclosing = self
+            .enclosing
+            .pop()
+            .unwrap_or_else(|| FunctionState::new(FunctionKind::Script, None));
+        mem::replace(&mut self.state, enclosing).function
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current;
+        loop {
+            match self.lexer.next() {
+                Some(Ok(pos)) => match pos.token {
+                    Token::SingleComment | Token::MultiComment => continue,
+                    _ => {
+                        self.current = pos;
+                        return;
+                    }
+                },
+                Some(Err(err)) => self.scan_error(err),
+                None => {
+                    self.current = self.lexer.eof();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn consume(&mut self, expected: Token, err: RloxParseError) {
+        if self.current.token == expected {
+            self.advance();
+        } else {
+            self.error_at_current(err);
+        }
+    }
+
+    fn check(&self, expected: Token) -> bool {
+        self.current.token == expected
+    }
+
+    /// Advance only if the current token is `expected`.
+    fn matches(&mut self, expected: Token) -> bool {
+        if !self.check(expected) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn emit(&mut self, op: Opcode) {
+        self.state
+            .function
+            .chunk
+            .write_opcode(op, self.previous.line);
+    }
+
+    /// Emit a jump to be patched later, returns its offset.
+    fn emit_jump(&mut self, jump: Opcode) -> usize {
+        self.emit(jump);
+        self.state.function.chunk.code.len() - 1
+    }
+
+    fn patch_jump(&mut self, at: usize) {
+        if let Err(err) = self.state.function.chunk.patch_jump(at) {
+            self.error(err);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        let offset = self.state.function.chunk.code.len() - loop_start;
+        match u16::try_from(offset) {
+            Ok(offset) => self.emit(Opcode::Loop(offset)),
+            Err(_) => self.error(RloxParseError::LoopTooLarge),
+        }
+    }
+
+    /// Implicit return at the end of a function.
+    fn emit_return(&mut self) {
+        if self.state.kind == FunctionKind::Initializer {
+            self.emit(Opcode::GetLocal(0));
+        } else {
+            self.emit(Opcode::Nil);
+        }
+        self.emit(Opcode::Return);
+    }
+
+    /// Index of `value` in the constants, reusing an identical one.
+    fn add_constant(&mut self, value: Value) -> usize {
+        let key = match value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::Obj(r) => Some(ConstantKey::Obj(r)),
+            Value::Nil | Value::Bool(_) => None,
+        };
+        if let Some(&idx) = key.as_ref().and_then(|key| self.state.constants.get(key)) {
+            return idx;
+        }
+        let idx = self.state.function.chunk.write_value(value);
+        if let Some(key) = key {
+            self.state.constants.insert(key, idx);
+        }
+        idx
+    }
+
+    /// A constant for the opcodes carrying an [Index].
+    fn make_constant(&mut self, value: Value) -> Index {
+        let idx = self.add_constant(value);
+        Index::new(idx).unwrap_or_else(|| {
+            self.error(RloxParseError::TooManyConstant);
+            Index::from(0)
+        })
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let idx = self.add_constant(value);
+        if let Ok(idx) = u8::try_from(idx) {
+            self.emit(Opcode::Constant(idx));
+        } else if let Some(idx) = Index::new(idx) {
+            self.emit(Opcode::ConstantLong(idx));
+        } else {
+            self.error(RloxParseError::TooManyConstant);
+        }
+    }
+
+    /// Store the name of a global in the constants.
+    fn identifier_constant(&mut self, name: &[u8]) -> Index {
+        let name = self.heap.intern(&String::from_utf8_lossy(name));
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn declaration(&mut self) {
+        if self.matches(Token::Struct) {
+            self.struct_declaration();
+        } else if self.matches(Token::Fun) {
+            self.fun_declaration();
+        } else if self.matches(Token::Let) {
+            self.let_declaration();
+        } else {
+            self.statement();
+        }
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    /// Skip tokens until the start of the next statement.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(Token::EOF) {
+            if self.previous.token == Token::Semicolon {
+                return;
+            }
+            match self.current.token {
+                Token::Struct
+                | Token::Fun
+                | Token::Let
+                | Token::For
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn struct_declaration(&mut self) {
+        let Token::Identifier(name) = self.current.token else {
+            self.error_at_current(RloxParseError::ExpectedStructName);
+            return;
+        };
+        let global = self.parse_variable(RloxParseError::ExpectedStructName);
+        let constant = self.identifier_constant(name);
+        self.emit(Opcode::Class(constant));
+        self.define_variable(global);
+
+        self.structs.push(StructState {
+            has_superclass: false,
+        });
+        if self.matches(Token::Lesser) {
+            self.superclass(name);
+        }
+
+        // Keep the struct on the stack while its methods are attached.
+        self.named_variable(name, false);
+        self.consume(
+            Token::LeftBrace,
+            RloxParseError::ExpectedBraceBeforeStructBody,
+        );
+        while !self.check(Token::RightBrace) && !self.check(Token::EOF) {
+            self.method();
+        }
+        self.consume(
+            Token::RightBrace,
+            RloxParseError::ExpectedBraceAfterStructBody,
+        );
+        self.emit(Opcode::Pop);
+        if self
+            .structs
+            .pop()
+            .is_some_and(|current| current.has_superclass)
+        {
+            self.end_scope();
+        }
+    }
+
+    /// `< Name` after the name of a struct.
+    fn superclass(&mut self, name: &[u8]) {
+        let Token::Identifier(superclass) = self.current.token else {
+            self.error_at_current(RloxParseError::ExpectedSuperclassName);
+            return;
+        };
+        self.advance();
+        self.named_variable(superclass, false);
+        if superclass == name {
+            self.error(RloxParseError::InheritFromItself);
+        }
+        // The superclass stays in a local named `super`, captured by the methods.
+        self.begin_scope();
+        self.declare_local(b"super");
+        self.mark_initialized();
+        self.named_variable(name, false);
+        self.emit(Opcode::Inherit);
+        if let Some(current) = self.structs.last_mut() {
+            current.has_superclass = true;
+        }
+    }
+
+    fn method(&mut self) {
+        let Token::Identifier(name) = self.current.token else {
+            self.error_at_current(RloxParseError::ExpectedMethodName);
+            self.advance();
+            return;
+        };
+        self.advance();
+        let constant = self.identifier_constant(name);
+        let kind = if name == b"init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+        self.emit(Opcode::Method(constant));
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable(RloxParseError::ExpectedFunctionName);
+        // A function can refer to itself, its body is compiled later.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    /// Compile the parameters and body following the name of a function, and
+    /// load it on the stack.
+    fn function(&mut self, kind: FunctionKind) {
+        let name = match self.previous.token {
+            Token::Identifier(name) => name,
+            _ => b"",
+        };
+        self.begin_function(kind, name);
+        self.begin_scope();
+
+        self.consume(
+            Token::LeftParens,
+            RloxParseError::ExpectedParensAfterFunctionName,
+        );
+        if !self.check(Token::RightParens) {
+            loop {
+                if self.state.function.arity as usize == MAX_ARGS {
+                    self.error_at_current(RloxParseError::TooManyParameters);
+                } else {
+                    self.state.function.arity += 1;
+                }
+                let param = self.parse_variable(RloxParseError::ExpectedParameterName);
+                self.define_variable(param);
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            Token::RightParens,
+            RloxParseError::ExpectedParensAfterParameters,
+        );
+        self.consume(
+            Token::LeftBrace,
+            RloxParseError::ExpectedBraceBeforeFunctionBody,
+        );
+        self.block();
+
+        // No end_scope, the locals are discarded with the call frame.
+        let function = self.end_function();
+        let function = self.heap.alloc(Obj::Function(function));
+        let idx = self.make_constant(Value::Obj(function));
+        self.emit(Opcode::Closure(idx));
+    }
+
+    fn let_declaration(&mut self) {
+        let global = self.parse_variable(RloxParseError::ExpectedVariableName);
+        if self.matches(Token::Equal) {
+            self.expression();
+        } else {
+            self.emit(Opcode::Nil);
+        }
+        self.consume(
+            Token::Semicolon,
+            RloxParseError::ExpectedSemicolonAfterVariable,
+        );
+        self.define_variable(global);
+    }
+
+    /// Consume a variable name and declare it.
+    ///
+    /// Returns the constant holding the name of a global, unused for locals.
+    fn parse_variable(&mut self, err: RloxParseError) -> Index {
+        match self.current.token {
+            Token::Identifier(name) => {
+                self.advance();
+                if self.state.scope_depth > 0 {
+                    self.declare_local(name);
+                    Index::from(0)
+                } else {
+                    self.identifier_constant(name)
+                }
+            }
+            _ => {
+                self.error_at_current(err);
+                Index::from(0)
+            }
+        }
+    }
+
+    fn declare_local(&mut self, name: &'a [u8]) {
+        let redeclared = self
+            .state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| {
+                local
+                    .depth
+                    .is_none_or(|depth| depth >= self.state.scope_depth)
+            })
+            .any(|local| local.name == name);
+        if redeclared {
+            self.error(RloxParseError::AlreadyDeclared);
+        }
+        if self.state.locals.len() == MAX_LOCALS {
+            self.error(RloxParseError::TooManyLocals);
+            return;
+        }
+        self.state.locals.push(Local {
+            name,
+            depth: None,
+            captured: false,
+        });
+    }
+
+    /// The local declared last is usable from now on.
+    fn mark_initialized(&mut self) {
+        if self.state.scope_depth > 0 {
+            if let Some(local) = self.state.locals.last_mut() {
+                local.depth = Some(self.state.scope_depth);
+            }
+        }
+    }
+
+    /// The variable is usable once its initializer is compiled.
+    fn define_variable(&mut self, global: Index) {
+        if self.state.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit(Opcode::DefineGlobal(global));
+        }
+    }
+
+    /// Slot of the innermost local named `name`.
+    ///
+    /// `level` is the index of the function in the enclosing ones, or their
+    /// count for the current function.
+    fn resolve_local(&mut self, level: usize, name: &[u8]) -> Option<u8> {
+        let (slot, local) = self
+            .state_at(level)
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error(RloxParseError::ReadLocalInInitializer);
+        }
+        Some(slot as u8)
+    }
+
+    /// Index of the upvalue of the function at `level` capturing `name`, found
+    /// in the locals of the enclosing functions.
+    fn resolve_upvalue(&mut self, level: usize, name: &[u8]) -> Option<u8> {
+        let enclosing = level.checked_sub(1)?;
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.state_at(enclosing).locals[slot as usize].captured = true;
+            return Some(self.add_upvalue(
+                level,
+                Capture {
+                    local: true,
+                    index: slot,
+                },
+            ));
+        }
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(
+            level,
+            Capture {
+                local: false,
+                index,
+            },
+        ))
+    }
+
+    fn add_upvalue(&mut self, level: usize, capture: Capture) -> u8 {
+        let upvalues = &self.state_at(level).function.upvalues;
+        if let Some(index) = upvalues.iter().position(|&upvalue| upvalue == capture) {
+            return index as u8;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error(RloxParseError::TooManyUpvalues);
+            return 0;
+        }
+        let upvalues = &mut self.state_at(level).function.upvalues;
+        upvalues.push(capture);
+        (upvalues.len() - 1) as u8
+    }
+
+    /// The function being compiled, or one of the enclosing ones.
+    fn state_at(&mut self, level: usize) -> &mut FunctionState<'a> {
+        match self.enclosing.get_mut(level) {
+            Some(state) => state,
+            None => &mut self.state,
+        }
+    }
+
+    fn statement(&mut self) {
+        if self.matches(Token::Print) {
+            self.print_statement();
+        } else if self.matches(Token::If) {
+            self.if_statement();
+        } else if self.matches(Token::While) {
+            self.while_statement();
+        } else if self.matches(Token::For) {
+            self.for_statement();
+        } else if self.matches(Token::Loop) {
+            self.loop_statement();
+        } else if self.matches(Token::Break) {
+            self.break_statement();
+        } else if self.matches(Token::Continue) {
+            self.continue_statement();
+        } else if self.matches(Token::Return) {
+            self.return_statement();
+        } else if self.matches(Token::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(Token::RightBrace) && !self.check(Token::EOF) {
+            self.declaration();
+        }
+        self.consume(Token::RightBrace, RloxParseError::ExpectedBlockEnd);
+    }
+
+    fn begin_scope(&mut self) {
+        self.state.scope_depth += 1;
+    }
+
+    /// Pop the locals of the block.
+    fn end_scope(&mut self) {
+        self.state.scope_depth -= 1;
+        while let Some(&Local {
+            depth: Some(depth),
+            captured,
+            ..
+        }) = self.state.locals.last()
+        {
+            if depth <= self.state.scope_depth {
+                break;
+            }
+            self.emit_pop_local(captured);
+            self.state.locals.pop();
+        }
+    }
+
+    /// Captured locals outlive the stack.
+    fn emit_pop_local(&mut self, captured: bool) {
+        if captured {
+            self.emit(Opcode::CloseUpvalue);
+        } else {
+            self.emit(Opcode::Pop);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(Token::LeftParens, RloxParseError::ExpectedParensAfterIf);
+        self.expression();
+        self.consume(
+            Token::RightParens,
+            RloxParseError::ExpectedParensAfterCondition,
+        );
+
+        let then_jump = self.emit_jump(Opcode::JumpIfFalse(0));
+        self.emit(Opcode::Pop);
+        self.statement();
+        let else_jump = self.emit_jump(Opcode::Jump(0));
+        self.patch_jump(then_jump);
+        self.emit(Opcode::Pop);
+        if self.matches(Token::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.state.function.chunk.code.len();
+        self.consume(Token::LeftParens, RloxParseError::ExpectedParensAfterWhile);
+        self.expression();
+        self.consume(
+            Token::RightParens,
+            RloxParseError::ExpectedParensAfterCondition,
+        );
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse(0));
+        self.emit(Opcode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.emit(Opcode::Pop);
+        self.end_loop();
+    }
+
+    /// `for (init; cond; incr) body`, the increment is compiled before the body
+    /// and jumped over on the way in.
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(Token::LeftParens, RloxParseError::ExpectedParensAfterFor);
+        if self.matches(Token::Semicolon) {
+            // No initializer.
+        } else if self.matches(Token::Let) {
+            self.let_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.state.function.chunk.code.len();
+        let mut exit_jump = None;
+        if !self.matches(Token::Semicolon) {
+            self.expression();
+            self.consume(
+                Token::Semicolon,
+                RloxParseError::ExpectedSemicolonAfterCondition,
+            );
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse(0)));
+            self.emit(Opcode::Pop);
+        }
+
+        if !self.matches(Token::RightParens) {
+            let body_jump = self.emit_jump(Opcode::Jump(0));
+            let increment_start = self.state.function.chunk.code.len();
+            self.expression();
+            self.emit(Opcode::Pop);
+            self.consume(
+                Token::RightParens,
+                RloxParseError::ExpectedParensAfterClauses,
+            );
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
         }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(Opcode::Pop);
+        }
+        self.end_loop();
+        self.end_scope();
+    }
+
+    /// `loop { body }` only exits through a `break`.
+    fn loop_statement(&mut self) {
+        let loop_start = self.state.function.chunk.code.len();
+        self.consume(Token::LeftBrace, RloxParseError::ExpectedBraceAfterLoop);
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_loop(loop_start);
+        self.end_loop();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.state.loops.push(Loop {
+            start,
+            scope_depth: self.state.scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Point the `break`s of the innermost loop here.
+    fn end_loop(&mut self) {
+        if let Some(innermost) = self.state.loops.pop() {
+            for at in innermost.breaks {
+                self.patch_jump(at);
+            }
+        }
+    }
+
+    /// Pop the locals of the scopes left by a jump out of the loop body, they
+    /// stay declared for the rest of the block.
+    fn pop_loop_locals(&mut self, scope_depth: usize) {
+        let captured: Vec<_> = self
+            .state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_some_and(|depth| depth > scope_depth))
+            .map(|local| local.captured)
+            .collect();
+        for captured in captured {
+            self.emit_pop_local(captured);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(
+            Token::Semicolon,
+            RloxParseError::ExpectedSemicolonAfterBreak,
+        );
+        let Some(scope_depth) = self
+            .state
+            .loops
+            .last()
+            .map(|innermost| innermost.scope_depth)
+        else {
+            self.error(RloxParseError::BreakOutsideLoop);
+            return;
+        };
+        self.pop_loop_locals(scope_depth);
+        let jump = self.emit_jump(Opcode::Jump(0));
+        if let Some(innermost) = self.state.loops.last_mut() {
+            innermost.breaks.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(
+            Token::Semicolon,
+            RloxParseError::ExpectedSemicolonAfterContinue,
+        );
+        let Some((start, scope_depth)) = self
+            .state
+            .loops
+            .last()
+            .map(|innermost| (innermost.start, innermost.scope_depth))
+        else {
+            self.error(RloxParseError::ContinueOutsideLoop);
+            return;
+        };
+        self.pop_loop_locals(scope_depth);
+        self.emit_loop(start);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state.kind == FunctionKind::Script {
+            self.error(RloxParseError::ReturnFromScript);
+        }
+        if self.matches(Token::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state.kind == FunctionKind::Initializer {
+                self.error(RloxParseError::ReturnValueFromInitializer);
+            }
+            self.expression();
+            self.consume(
+                Token::Semicolon,
+                RloxParseError::ExpectedSemicolonAfterReturn,
+            );
+            self.emit(Opcode::Return);
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(
+            Token::Semicolon,
+            RloxParseError::ExpectedSemicolonAfterValue,
+        );
+        self.emit(Opcode::Print);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(
+            Token::Semicolon,
+            RloxParseError::ExpectedSemicolonAfterExpression,
+        );
+        self.emit(Opcode::Pop);
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let prefix = match get_rule(&self.previous.token).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error(RloxParseError::ExpectedExpression);
+                return;
+            }
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+
+        while precedence <= get_rule(&self.current.token).precedence {
+            self.advance();
+            if let Some(infix) = get_rule(&self.previous.token).infix {
+                infix(self, can_assign);
+            }
+        }
+
+        if can_assign && self.matches(Token::Equal) {
+            self.error(RloxParseError::InvalidAssignmentTarget);
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        if let Token::Number(n) = self.previous.token {
+            // Small positive integers are stored directly in the opcode.
+            if n.fract() == 0. && n.is_sign_positive() && n <= u16::MAX as f64 {
+                self.emit(Opcode::Litteral(n as u16));
+            } else {
+                self.emit_constant(Value::Number(n));
+            }
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        if let Token::String(s) = self.previous.token {
+            // The lexer slices a `&str` on ascii quotes, so it is still valid utf8.
+            let s = String::from_utf8_lossy(s);
+            let s = self.heap.intern(&s);
+            self.emit_constant(Value::Obj(s));
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        if let Token::Identifier(name) = self.previous.token {
+            self.named_variable(name, can_assign);
+        }
+    }
+
+    fn named_variable(&mut self, name: &[u8], can_assign: bool) {
+        let level = self.enclosing.len();
+        let (get, set) = if let Some(slot) = self.resolve_local(level, name) {
+            (Opcode::GetLocal(slot), Opcode::SetLocal(slot))
+        } else if let Some(upvalue) = self.resolve_upvalue(level, name) {
+            (Opcode::GetUpvalue(upvalue), Opcode::SetUpvalue(upvalue))
+        } else {
+            let global = self.identifier_constant(name);
+            (Opcode::GetGlobal(global), Opcode::SetGlobal(global))
+        };
+        if can_assign && self.matches(Token::Equal) {
+            self.expression();
+            self.emit(set);
+        } else {
+            self.emit(get);
+        }
+    }
+
+    /// `[a, b, c]`, the elements are collected from the stack.
+    fn array(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        if !self.check(Token::RightSquare) {
+            loop {
+                self.expression();
+                if count == MAX_ARGS {
+                    self.error(RloxParseError::TooManyElements);
+                } else {
+                    count += 1;
+                }
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            Token::RightSquare,
+            RloxParseError::ExpectedSquareAfterElements,
+        );
+        self.emit(Opcode::Array(count as u8));
+    }
+
+    /// `{ key: value }`, a `{` starting a statement is a block instead.
+    fn map(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        if !self.check(Token::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(Token::Colon, RloxParseError::ExpectedColonAfterKey);
+                self.expression();
+                if count == MAX_ARGS {
+                    self.error(RloxParseError::TooManyEntries);
+                } else {
+                    count += 1;
+                }
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightBrace, RloxParseError::ExpectedBraceAfterEntries);
+        self.emit(Opcode::Map(count as u8));
+    }
+
+    /// `value[index]`, read or assigned.
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(Token::RightSquare, RloxParseError::ExpectedSquareAfterIndex);
+        if can_assign && self.matches(Token::Equal) {
+            self.expression();
+            self.emit(Opcode::SetIndex);
+        } else {
+            self.emit(Opcode::GetIndex);
+        }
+    }
+
+    fn self_(&mut self, _can_assign: bool) {
+        if self.structs.is_empty() {
+            self.error(RloxParseError::SelfOutsideStruct);
+            return;
+        }
+        self.named_variable(b"self", false);
+    }
+
+    /// Method of the superclass, bound to `self` or called right away.
+    fn super_(&mut self, _can_assign: bool) {
+        match self.structs.last() {
+            None => self.error(RloxParseError::SuperOutsideStruct),
+            Some(current) if !current.has_superclass => {
+                self.error(RloxParseError::SuperWithoutSuperclass)
+            }
+            Some(_) => (),
+        }
+        self.consume(Token::Dot, RloxParseError::ExpectedDotAfterSuper);
+        let Token::Identifier(name) = self.current.token else {
+            self.error_at_current(RloxParseError::ExpectedSuperclassMethodName);
+            return;
+        };
+        self.advance();
+        let name = self.identifier_constant(name);
+
+        self.named_variable(b"self", false);
+        match u8::try_from(name.get()) {
+            Ok(short) if self.matches(Token::LeftParens) => {
+                let count = self.argument_list();
+                self.named_variable(b"super", false);
+                self.emit(Opcode::SuperInvoke(short, count));
+            }
+            _ => {
+                self.named_variable(b"super", false);
+                self.emit(Opcode::GetSuper(name));
+                // Too far to be invoked, bind the method then call it.
+                if self.matches(Token::LeftParens) {
+                    let count = self.argument_list();
+                    self.emit(Opcode::Call(count));
+                }
+            }
+        }
+    }
+
+    /// Property access, assignment or method call.
+    fn dot(&mut self, can_assign: bool) {
+        let Token::Identifier(name) = self.current.token else {
+            self.error_at_current(RloxParseError::ExpectedPropertyName);
+            return;
+        };
+        self.advance();
+        let name = self.identifier_constant(name);
+        if can_assign && self.matches(Token::Equal) {
+            self.expression();
+            self.emit(Opcode::SetProperty(name));
+        } else if self.matches(Token::LeftParens) {
+            if let Ok(short) = u8::try_from(name.get()) {
+                let count = self.argument_list();
+                self.emit(Opcode::Invoke(short, count));
+            } else {
+                // Too far to be invoked, read the method then call it.
+                self.emit(Opcode::GetProperty(name));
+                let count = self.argument_list();
+                self.emit(Opcode::Call(count));
+            }
+        } else {
+            self.emit(Opcode::GetProperty(name));
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous.token {
+            Token::False => self.emit(Opcode::False),
+            Token::True => self.emit(Opcode::True),
+            Token::Nil => self.emit(Opcode::Nil),
+            _ => unreachable!(),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(Token::RightParens, RloxParseError::UnclosedParens);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous.token;
+        self.parse_precedence(Precedence::Unary);
+        match operator {
+            Token::Minus => self.emit(Opcode::Negate),
+            Token::Bang => self.emit(Opcode::Not),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Skip the right operand when the left one is falsey, leaving it as result.
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Opcode::JumpIfFalse(0));
+        self.emit(Opcode::Pop);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    /// Skip the right operand when the left one is truthy, leaving it as result.
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse(0));
+        let end_jump = self.emit_jump(Opcode::Jump(0));
+        self.patch_jump(else_jump);
+        self.emit(Opcode::Pop);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let count = self.argument_list();
+        self.emit(Opcode::Call(count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut count = 0;
+        if !self.check(Token::RightParens) {
+            loop {
+                self.expression();
+                if count == MAX_ARGS {
+                    self.error(RloxParseError::TooManyArguments);
+                } else {
+                    count += 1;
+                }
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            Token::RightParens,
+            RloxParseError::ExpectedParensAfterArguments,
+        );
+        count as u8
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous.token;
+        let rule = get_rule(&operator);
+        self.parse_precedence(rule.precedence.next());
+        match operator {
+            Token::Plus => self.emit(Opcode::Add),
+            Token::Minus => self.emit(Opcode::Sub),
+            Token::Star => self.emit(Opcode::Mul),
+            Token::Slash => self.emit(Opcode::Div),
+            Token::EqualEqual => self.emit(Opcode::Equal),
+            Token::Greater => self.emit(Opcode::Greater),
+            Token::Lesser => self.emit(Opcode::Less),
+            // a != b is !(a == b), a >= b is !(a < b) and a <= b is !(a > b).
+            Token::BangEqual => {
+                self.emit(Opcode::Equal);
+                self.emit(Opcode::Not);
+            }
+            Token::GreaterEqual => {
+                self.emit(Opcode::Less);
+                self.emit(Opcode::Not);
+            }
+            Token::LesserEqual => {
+                self.emit(Opcode::Greater);
+                self.emit(Opcode::Not);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn error(&mut self, err: RloxParseError) {
+        self.error_at(self.previous, err);
+    }
+
+    fn error_at_current(&mut self, err: RloxParseError) {
+        self.error_at(self.current, err);
+    }
+
+    fn scan_error(&mut self, err: ScanError) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.errors.push(Diagnostic::new(
+            self.source,
+            self.file,
+            (err.line, err.column),
+            err.start..err.end,
+            String::new(),
+            err.message(),
+        ));
+    }
+
+    fn error_at(&mut self, pos: TokenPos, err: RloxParseError) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let at = match pos.token {
+            Token::EOF => " at end".to_string(),
+            token => format!(" at '{}'", token),
+        };
+        self.errors.push(Diagnostic::new(
+            self.source,
+            self.file,
+            (pos.line, pos.column),
+            pos.start..pos.end,
+            at,
+            err.into(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test_compiler {
+    use super::*;
+    use crate::Chunk;
+
+    /// Chunk of the script.
+    fn compile(code: &str) -> Result<Chunk, Vec<String>> {
+        Compiler::new(code, &mut Heap::new())
+            .compile()
+            .map(|script| script.chunk)
+            .map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    #[test]
+    fn test_precedence() {
+        let chunk = compile("1 + 2 * 3;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Litteral(3),
+                Opcode::Mul,
+                Opcode::Add,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grouping_and_negate() {
+        let chunk = compile("-(1.5 - 2) / 4;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Constant(0),
+                Opcode::Litteral(2),
+                Opcode::Sub,
+                Opcode::Negate,
+                Opcode::Litteral(4),
+                Opcode::Div,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert_eq!(chunk.values, [Value::Number(1.5)]);
+    }
+
+    #[test]
+    fn test_left_associative() {
+        let chunk = compile("1 - 2 - 3;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Sub,
+                Opcode::Litteral(3),
+                Opcode::Sub,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        let chunk = compile("-(nil + true) * false;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Nil,
+                Opcode::True,
+                Opcode::Add,
+                Opcode::Negate,
+                Opcode::False,
+                Opcode::Mul,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let chunk = compile("!(1 <= 2) != 3 > 4;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Greater,
+                Opcode::Not,
+                Opcode::Not,
+                Opcode::Litteral(3),
+                Opcode::Litteral(4),
+                Opcode::Greater,
+                Opcode::Equal,
+                Opcode::Not,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::new("\"a\" + \"b\";", &mut heap)
+            .compile()
+            .unwrap()
+            .chunk;
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Constant(0),
+                Opcode::Constant(1),
+                Opcode::Add,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return
+            ]
+        );
+        let values: Vec<_> = chunk
+            .values
+            .iter()
+            .map(|v| v.display(&heap).to_string())
+            .collect();
+        assert_eq!(values, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_strings_interned() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::new("\"a\" + \"a\";", &mut heap)
+            .compile()
+            .unwrap()
+            .chunk;
+        // The same string is a single constant.
+        assert_eq!(chunk.values.len(), 1);
+        assert_eq!(chunk.code[..2], [Opcode::Constant(0), Opcode::Constant(0)]);
+    }
+
+    #[test]
+    fn test_statements() {
+        let chunk = compile("print 1;\n2;\nprint 3;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Print,
+                Opcode::Litteral(2),
+                Opcode::Pop,
+                Opcode::Litteral(3),
+                Opcode::Print,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("print 1").is_err());
+        assert!(compile("1 2;").is_err());
+    }
+
+    #[test]
+    fn test_globals() {
+        let chunk = compile("let a = 1;\nlet b;\nb = a = 2;\nprint a;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::DefineGlobal(0.into()),
+                Opcode::Nil,
+                Opcode::DefineGlobal(1.into()),
+                Opcode::Litteral(2),
+                Opcode::SetGlobal(0.into()),
+                Opcode::SetGlobal(1.into()),
+                Opcode::Pop,
+                Opcode::GetGlobal(0.into()),
+                Opcode::Print,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("let 1 = 2;").is_err());
+        assert!(compile("let a = 1").is_err());
+        assert!(compile("1 + a = 2;").is_err());
+    }
+
+    #[test]
+    fn test_locals() {
+        let chunk = compile("{ let a = 1; { let b = a; a = b; } print a; }").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::GetLocal(1),
+                Opcode::GetLocal(2),
+                Opcode::SetLocal(1),
+                Opcode::Pop,
+                Opcode::Pop,
+                Opcode::GetLocal(1),
+                Opcode::Print,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(chunk.values.is_empty());
+    }
+
+    #[test]
+    fn test_locals_errors() {
+        assert!(compile("{ let a = a; }").is_err());
+        assert!(compile("{ let a = 1; let a = 2; }").is_err());
+        assert!(compile("{ let a = 1; { let a = a; } }").is_err());
+        assert!(compile("{ let a = 1; { let a = 2; } }").is_ok());
+        assert!(compile("{ print 1;").is_err());
+        let many: String = (0..257).map(|i| format!("let a{} = {};", i, i)).collect();
+        assert!(compile(&format!("{{ {} }}", many)).is_err());
+    }
+
+    #[test]
+    fn test_if_else() {
+        let chunk = compile("if (true) print 1; else print 2;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::True,
+                Opcode::JumpIfFalse(5),
+                Opcode::Pop,
+                Opcode::Litteral(1),
+                Opcode::Print,
+                Opcode::Jump(4),
+                Opcode::Pop,
+                Opcode::Litteral(2),
+                Opcode::Print,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("if true print 1;").is_err());
+        assert!(compile("while (true print 1;").is_err());
+    }
+
+    #[test]
+    fn test_while() {
+        let chunk = compile("while (false) print 1;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::False,
+                Opcode::JumpIfFalse(5),
+                Opcode::Pop,
+                Opcode::Litteral(1),
+                Opcode::Print,
+                Opcode::Loop(5),
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_for() {
+        let chunk = compile("for (let i = 0; i < 2; i = i + 1) print i;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(0),
+                Opcode::GetLocal(1),
+                Opcode::Litteral(2),
+                Opcode::Less,
+                Opcode::JumpIfFalse(12),
+                Opcode::Pop,
+                Opcode::Jump(7),
+                Opcode::GetLocal(1),
+                Opcode::Litteral(1),
+                Opcode::Add,
+                Opcode::SetLocal(1),
+                Opcode::Pop,
+                Opcode::Loop(11),
+                Opcode::GetLocal(1),
+                Opcode::Print,
+                Opcode::Loop(8),
+                Opcode::Pop,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("for (;;) {}").is_ok());
+        assert!(compile("for (let i = 0; i < 2) {}").is_err());
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let chunk = compile("loop { let a = 1; { let b = 2; break; } }").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Pop,
+                Opcode::Pop,
+                Opcode::Jump(4),
+                Opcode::Pop,
+                Opcode::Pop,
+                Opcode::Loop(7),
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("while (true) { continue; }").is_ok());
+        assert!(compile("break;").is_err());
+        assert!(compile("{ continue; }").is_err());
+        assert!(compile("loop print 1;").is_err());
+    }
+
+    #[test]
+    fn test_logical() {
+        let chunk = compile("1 or 2 and 3;").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::JumpIfFalse(2),
+                Opcode::Jump(6),
+                Opcode::Pop,
+                Opcode::Litteral(2),
+                Opcode::JumpIfFalse(3),
+                Opcode::Pop,
+                Opcode::Litteral(3),
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("1 and;").is_err());
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::new("fun add(a, b) { return a + b; }\nadd(1, 2);", &mut heap)
+            .compile()
+            .unwrap()
+            .chunk;
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Closure(1.into()),
+                Opcode::DefineGlobal(0.into()),
+                Opcode::GetGlobal(0.into()),
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Call(2),
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        let Value::Obj(add) = chunk.values[1] else {
+            panic!("Expected a function.");
+        };
+        let add = heap.as_function(add).unwrap();
+        assert_eq!(add.arity, 2);
+        assert_eq!(add.name.as_deref(), Some("add"));
+        assert_eq!(
+            add.chunk.code,
+            [
+                Opcode::GetLocal(1),
+                Opcode::GetLocal(2),
+                Opcode::Add,
+                Opcode::Return,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upvalues() {
+        let mut heap = Heap::new();
+        let code = "{
+    let a = 1;
+    fun outer() {
+        let b = 2;
+        fun inner() { a = b + a; }
+        return inner;
+    }
+}";
+        let chunk = Compiler::new(code, &mut heap).compile().unwrap().chunk;
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Closure(0.into()),
+                Opcode::Pop,
+                Opcode::CloseUpvalue,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        let outer = match chunk.values[0] {
+            Value::Obj(r) => heap.as_function(r).unwrap(),
+            _ => panic!("Expected a function."),
+        };
+        assert_eq!(
+            outer.upvalues,
+            [Capture {
+                local: true,
+                index: 1
+            }]
+        );
+        let inner = match outer.chunk.values[0] {
+            Value::Obj(r) => heap.as_function(r).unwrap(),
+            _ => panic!("Expected a function."),
+        };
+        // `a`, resolved first, goes through the upvalue of outer.
+        assert_eq!(
+            inner.upvalues,
+            [
+                Capture {
+                    local: false,
+                    index: 0
+                },
+                Capture {
+                    local: true,
+                    index: 1
+                }
+            ]
+        );
+        assert_eq!(
+            inner.chunk.code,
+            [
+                Opcode::GetUpvalue(1),
+                Opcode::GetUpvalue(0),
+                Opcode::Add,
+                Opcode::SetUpvalue(0),
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_structs() {
+        let chunk = compile("struct A { m() { return self; } }\nA().m();").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Class(0.into()),
+                Opcode::DefineGlobal(0.into()),
+                Opcode::GetGlobal(0.into()),
+                Opcode::Closure(2.into()),
+                Opcode::Method(1.into()),
+                Opcode::Pop,
+                Opcode::GetGlobal(0.into()),
+                Opcode::Call(0),
+                Opcode::Invoke(1, 0),
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("struct A { init() { return; } }").is_ok());
+        assert!(compile("{ struct A { f() { fun g() { return self; } } } }").is_ok());
+    }
+
+    #[test]
+    fn test_structs_errors() {
+        assert!(compile("print self;").is_err());
+        assert!(compile("fun f() { return self; }").is_err());
+        assert!(compile("struct A { init() { return 1; } }").is_err());
+        assert!(compile("struct { }").is_err());
+        assert!(compile("struct A { 1 }").is_err());
+        assert!(compile("struct A { m() {} ").is_err());
+        assert!(compile("let a; a.1;").is_err());
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let chunk = compile("struct A {}\nstruct B < A { m() { return super.m; } }").unwrap();
+        assert_eq!(
+            chunk.code[4..],
+            [
+                Opcode::Class(1.into()),
+                Opcode::DefineGlobal(1.into()),
+                Opcode::GetGlobal(0.into()),
+                Opcode::GetGlobal(1.into()),
+                Opcode::Inherit,
+                Opcode::GetGlobal(1.into()),
+                Opcode::Closure(3.into()),
+                Opcode::Method(2.into()),
+                Opcode::Pop,
+                Opcode::CloseUpvalue,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        let errors = [
+            "struct A < A {}",
+            "struct A < {}",
+            "super.m();",
+            "struct A { m() { super.m(); } }",
+            "struct A {} struct B < A { m() { super; } }",
+            "struct A {} struct B < A { m() { super.1; } }",
+        ];
+        for code in errors {
+            assert!(compile(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_arrays() {
+        let chunk = compile("let a = [1, 2];\na[0] = a[1];").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Array(2),
+                Opcode::DefineGlobal(0.into()),
+                Opcode::GetGlobal(0.into()),
+                Opcode::Litteral(0),
+                Opcode::GetGlobal(0.into()),
+                Opcode::Litteral(1),
+                Opcode::GetIndex,
+                Opcode::SetIndex,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("[];").is_ok());
+        assert!(compile("[1, 2;").is_err());
+        assert!(compile("a[1;").is_err());
+        assert!(compile("a + b[0] = 1;").is_err());
+    }
+
+    #[test]
+    fn test_maps() {
+        let chunk = compile("let m = {\"k\": 1, 2: {}};\n{ m[true]; }").unwrap();
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Constant(1),
+                Opcode::Litteral(1),
+                Opcode::Litteral(2),
+                Opcode::Map(0),
+                Opcode::Map(2),
+                Opcode::DefineGlobal(0.into()),
+                Opcode::GetGlobal(0.into()),
+                Opcode::True,
+                Opcode::GetIndex,
+                Opcode::Pop,
+                Opcode::Nil,
+                Opcode::Return,
+            ]
+        );
+        assert!(compile("print {\"k\" 1};").is_err());
+        assert!(compile("print {1: 2,};").is_err());
+        assert!(compile("print {1: 2;").is_err());
+    }
+
+    #[test]
+    fn test_constants() {
+        // Numbers are stored once, and a name shares the constant of its string.
+        let chunk = compile("print 0.5 + 0.5 + -0.5; print \"a\"; print a;").unwrap();
+        assert_eq!(chunk.values.len(), 2);
+        assert_eq!(chunk.code[..2], [Opcode::Constant(0), Opcode::Constant(0)]);
+        assert_eq!(chunk.code[7..9], [Opcode::Constant(1), Opcode::Print]);
+        assert_eq!(chunk.code[9], Opcode::GetGlobal(1.into()));
+
+        let code: String = (0..300).map(|i| format!("print {}.5;", i)).collect();
+        let chunk = compile(&code).unwrap();
+        assert_eq!(chunk.values.len(), 300);
+        assert_eq!(chunk.code[2 * 255], Opcode::Constant(255));
+        assert_eq!(
+            chunk.code[2 * 256],
+            Opcode::ConstantLong(Index::new(256).unwrap())
+        );
+        assert_eq!(
+            chunk.code[2 * 299],
+            Opcode::ConstantLong(Index::new(299).unwrap())
+        );
+
+        // Names past the first 256 constants are indexed on three bytes, and a
+        // method too far to be invoked is read then called.
+        let code: String = (0..300).map(|i| format!("let v{} = nil;", i)).collect();
+        let chunk = compile(&format!("{}v299.m(v0);", code)).unwrap();
+        let long = |i| Index::new(i).unwrap();
+        assert_eq!(chunk.code[2 * 299 + 1], Opcode::DefineGlobal(long(299)));
+        assert_eq!(
+            chunk.code[600..605],
+            [
+                Opcode::GetGlobal(long(299)),
+                Opcode::GetProperty(long(300)),
+                Opcode::GetGlobal(0.into()),
+                Opcode::Call(1),
+                Opcode::Pop,
+            ]
+        );
+        assert_eq!(Index::new(0x100_0000), None);
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let code = "print 1 +;
+let = 2;
+print (3;
+fun f( { }
+print 4;
+print 1 1 1;
+let x = @;
+print x
+print 5;";
+        assert_eq!(
+            compile(code).unwrap_err(),
+            [
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at '=': Expect variable name.",
+                "[line 3] Error at ';': Expect ')' after expression.",
+                "[line 4] Error at '{': Expect parameter name.",
+                "[line 6] Error at '1': Expect ';' after value.",
+                "[line 7] Error: Unexpected character.",
+                "[line 9] Error at 'print': Expect ';' after value.",
+            ]
+        );
+        // Nothing is reported after the first error of a statement.
+        assert_eq!(compile("print (((;").unwrap_err().len(), 1);
+        assert_eq!(
+            compile("print 1").unwrap_err(),
+            ["[line 1] Error at end: Expect ';' after value."]
+        );
+    }
+
+    #[test]
+    fn test_functions_errors() {
+        assert!(compile("return 1;").is_err());
+        assert!(compile("fun f(a, 1) {}").is_err());
+        assert!(compile("fun f() { return 1 }").is_err());
+        assert!(compile("fun () {}").is_err());
+        assert!(compile("f(1, 2;").is_err());
+        let params: Vec<_> = (0..256).map(|i| format!("p{}", i)).collect();
+        assert!(compile(&format!("fun f({}) {{}}", params.join(", "))).is_err());
+        assert!(compile(&format!("fun f({}) {{}}", params[1..].join(", "))).is_ok());
+    }
+
+    #[test]
+    fn test_lines() {
+        let chunk = compile("1 +\n\n2;").unwrap();
+        assert_eq!(chunk.lines, [(1, 1), (5, 3)]);
+        // Lines past u16::MAX are kept as is.
+        let chunk = compile(&format!("{}1;", "\n".repeat(70_000))).unwrap();
+        assert_eq!(chunk.lines, [(4, 70_001)]);
+    }
+
+    #[test]
+    fn test_scan_errors() {
+        assert!(compile("1 + @;").is_err());
+        assert!(compile("1 /* 2;").is_err());
+    }
+
+    #[test]
+    fn test_unclosed_parens() {
+        assert!(compile("(1 + 2;").is_err());
+    }
+
+    #[test]
+    fn test_missing_operand() {
+        assert!(compile("1 +;").is_err());
+        assert!(compile("* 2;").is_err());
     }
 }
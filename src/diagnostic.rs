@@ -0,0 +1,186 @@
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// An error located in the source code.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    /// None when the code does not come from a file, like in the REPL.
+    pub file: Option<String>,
+    /// Starting at 1.
+    pub line: u32,
+    /// Starting at 1, counted in utf8 characters.
+    pub column: usize,
+    /// Byte offsets of the faulty lexeme in the source.
+    pub span: Range<usize>,
+    /// Like ` at ')'` or ` at end`, empty when no token could be read.
+    pub at: String,
+    pub message: &'static str,
+    // The source line where the span starts, kept to be rendered.
+    source_line: String,
+    // Byte offset of the span in `source_line`.
+    offset: usize,
+}
+
+impl Diagnostic {
+    pub fn new(
+        source: &str,
+        file: Option<&str>,
+        (line, column): (u32, usize),
+        span: Range<usize>,
+        at: String,
+        message: &'static str,
+    ) -> Diagnostic {
+        let bytes = source.as_bytes();
+        let start = span.start.min(bytes.len());
+        let line_start = bytes[..start]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = bytes[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |i| start + i);
+        let source_line = String::from_utf8_lossy(&bytes[line_start..line_end]);
+        Diagnostic {
+            file: file.map(String::from),
+            line,
+            column,
+            span,
+            at,
+            message,
+            source_line: source_line.trim_end_matches('\r').to_string(),
+            offset: start - line_start,
+        }
+    }
+
+    /// The message followed by the source line with the span underlined.
+    pub fn display(&self, color: bool) -> DiagnosticDisplay<'_> {
+        DiagnosticDisplay {
+            diagnostic: self,
+            color,
+        }
+    }
+}
+
+/// Only the first line, `[line 3] Error at ')': Expect expression.`.
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error{}: {}", self.line, self.at, self.message)
+    }
+}
+
+pub struct DiagnosticDisplay<'a> {
+    diagnostic: &'a Diagnostic,
+    color: bool,
+}
+
+impl Display for DiagnosticDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let d = self.diagnostic;
+        let (red, blue, reset) = if self.color {
+            (RED, BLUE, RESET)
+        } else {
+            ("", "", "")
+        };
+        writeln!(
+            f,
+            "{}[line {}] Error{}:{} {}",
+            red, d.line, d.at, reset, d.message
+        )?;
+        let gutter = " ".repeat(d.line.to_string().len());
+        if let Some(file) = &d.file {
+            writeln!(
+                f,
+                "{}{}-->{} {}:{}:{}",
+                gutter, blue, reset, file, d.line, d.column
+            )?;
+        }
+        write!(f, "{}{} |{}", blue, d.line, reset)?;
+        if d.source_line.is_empty() {
+            writeln!(f)?;
+        } else {
+            writeln!(f, " {}", d.source_line)?;
+        }
+
+        // Keep the tabs so the carets line up with the source line.
+        let offset = d.offset.min(d.source_line.len());
+        let (before, after) = d.source_line.split_at(offset);
+        let padding: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = after
+            .char_indices()
+            .take_while(|&(i, _)| i < d.span.len())
+            .count()
+            .max(1);
+        write!(
+            f,
+            "{}{} |{} {}{}{}{}",
+            blue,
+            gutter,
+            reset,
+            padding,
+            red,
+            "^".repeat(carets),
+            reset
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_diagnostic {
+    use super::*;
+
+    fn diagnostic(source: &str, file: Option<&str>, span: Range<usize>, at: &str) -> Diagnostic {
+        Diagnostic::new(
+            source,
+            file,
+            (2, 7),
+            span,
+            at.to_string(),
+            "Expect expression.",
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let d = diagnostic("print 1;\nprint (1 + );\n", None, 20..21, " at ')'");
+        assert_eq!(d.to_string(), "[line 2] Error at ')': Expect expression.");
+        assert_eq!(
+            d.display(false).to_string(),
+            "[line 2] Error at ')': Expect expression.\n2 | print (1 + );\n  |            ^"
+        );
+    }
+
+    #[test]
+    fn test_render_file_and_span() {
+        // An unterminated string is underlined up to the end of its first line.
+        let d = diagnostic("x;\n\tlet é = \"abc\nd", Some("a.lox"), 13..19, "");
+        assert_eq!(
+            d.display(false).to_string(),
+            "[line 2] Error: Expect expression.\n --> a.lox:2:7\n2 | \tlet é = \"abc\n  | \t        ^^^^"
+        );
+    }
+
+    #[test]
+    fn test_render_end() {
+        let d = diagnostic("print 1", None, 7..7, " at end");
+        assert_eq!(
+            d.display(false).to_string(),
+            "[line 2] Error at end: Expect expression.\n2 | print 1\n  |        ^"
+        );
+        let d = diagnostic("print 1\n", None, 8..8, " at end");
+        assert_eq!(
+            d.display(false).to_string(),
+            "[line 2] Error at end: Expect expression.\n2 |\n  | ^"
+        );
+        let colored = d.display(true).to_string();
+        assert!(colored.starts_with("\x1b[1;31m[line 2] Error at end:\x1b[0m"));
+        assert!(colored.ends_with("\x1b[1;31m^\x1b[0m"));
+    }
+}
@@ -1,12 +1,6 @@
-use core::fmt;
 use std::str::FromStr;
-use std::{
-    fmt::{Display, Formatter},
-};
 
-use crate::lexer::{
-    ScanError, Token, keyword_or_ident,
-};
+use crate::lexer::{keyword_or_ident, ScanErrorKind, Token};
 
 #[inline]
 fn is_ascii_alphabetic_or_underscore(c: &u8) -> bool {
@@ -22,7 +16,7 @@ struct Scanner<'a> {
 }
 
 impl<'a> Scanner<'a> {
-    fn new(code: &'a str) -> Scanner {
+    fn new(code: &'a str) -> Scanner<'a> {
         Scanner {
             code: code.as_bytes(),
             // Only used in ident oupsy
@@ -38,7 +32,6 @@ impl<'a> Scanner<'a> {
             match c {
                 b' ' | b'\r' | b'\t' => {
                     self.advance();
-                    break;
                 }
                 b'\n' => {
                     self.line += 1;
@@ -61,7 +54,7 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn string(&mut self) -> Result<Token, ScanError> {
+    fn string(&mut self) -> Result<Token<'a>, ScanErrorKind> {
         while let Some(c) = self.peek() {
             if *c == b'"' || self.is_at_end() {
                 break;
@@ -73,40 +66,31 @@ impl<'a> Scanner<'a> {
         }
 
         if self.is_at_end() {
-            return Err(ScanError::UnmatchedString);
+            return Err(ScanErrorKind::UnmatchedString);
         }
         self.advance();
-        // TODO: Hack for empty strings, it's ugly.
-        // if self.cursor - self.start == 1 {
-        //     self.cursor += 1;
-        // }
-        Ok(Token::String)
+        // Without the quotes.
+        Ok(Token::String(&self.code[self.start + 1..self.cursor - 1]))
     }
 
-    fn numbers(&mut self) -> Result<Token, ScanError> {
-        let mut iter = self.code.iter().peekable();
-        while let Some(d) = iter.peek() {
-            if d.is_ascii_digit() {
-                iter.next();
-                self.advance();
-            } else {
+    fn digits(&mut self) {
+        while let Some(d) = self.peek() {
+            if !d.is_ascii_digit() {
                 break;
             }
+            self.advance();
         }
+    }
 
-        if iter.next() == Some(&b'.') {
-            self.advance();
-            for d in iter {
-                if d.is_ascii_digit() {
-                    self.advance();
-                } else {
-                    break;
-                }
-            }
+    fn numbers(&mut self) -> Result<Token<'a>, ScanErrorKind> {
+        self.digits();
+        if self.match_bin(b'.').is_some() {
+            self.digits();
         }
-        let s = unsafe { std::str::from_utf8_unchecked(&self.code[self.start..self.cursor]) };
+        let s = std::str::from_utf8(&self.code[self.start..self.cursor])
+            .map_err(|_| ScanErrorKind::NumberNotRecognized)?;
         Ok(Token::Number(
-            f32::from_str(s).map_err(|e| ScanError::NumberNotRecognized)?,
+            f64::from_str(s).map_err(|_| ScanErrorKind::NumberNotRecognized)?,
         ))
     }
 
@@ -117,7 +101,7 @@ impl<'a> Scanner<'a> {
         Some(c)
     }
 
-    fn identifier(&mut self) -> Result<Token, ScanError> {
+    fn identifier(&mut self) -> Result<Token<'a>, ScanErrorKind> {
         loop {
             match self.peek() {
                 Some(c) if is_ascii_alphabetic_or_underscore(c) || c.is_ascii_digit() => {
@@ -129,8 +113,8 @@ impl<'a> Scanner<'a> {
         self.identifier_type()
     }
 
-    fn identifier_type(&mut self) -> Result<Token, ScanError> {
-        let s = &self.code[self.start..self.cursor];
+    fn identifier_type(&mut self) -> Result<Token<'a>, ScanErrorKind> {
+        let s: &'a [u8] = &self.code[self.start..self.cursor];
         Ok(keyword_or_ident(s))
     }
 
@@ -164,10 +148,11 @@ impl<'a> Scanner<'a> {
     /// Internally advance the cursor on the current character.
     ///
     /// Returns either:
-    /// - A [Token], the line and the start position of the found token.
-    /// - A [ScanError] right now, the end of the parsing is... an error
+    /// - A [Token], the line and the length of the found token.
+    /// - A [ScanErrorKind] right now, the end of the parsing is... an error
+    ///
     /// May be changed in the future if I move to an Iterator.
-    fn scan_token(&mut self) -> Result<(Token, usize, usize), ScanError> {
+    fn scan_token(&mut self) -> Result<(Token<'a>, usize, usize), ScanErrorKind> {
         // TODO: Update lines.
         // TODO: Manage comments
 
@@ -175,8 +160,8 @@ impl<'a> Scanner<'a> {
         self.start = self.cursor;
 
         // TODO: Use advance?
-        //let c = self.code.get(self.cursor).ok_or(ScanError::End)?;
-        let c = self.advance().expect("End of scanning");
+        //let c = self.code.get(self.cursor).ok_or(ScanErrorKind::End)?;
+        let c = self.advance().ok_or(ScanErrorKind::End)?;
         //        self.cursor += 1;
 
         //let mut code = code.peekable();
@@ -194,36 +179,36 @@ impl<'a> Scanner<'a> {
             b'/' => Token::Slash,
             b'*' => Token::Star,
             b'!' => {
-                if let Some(_) = self.match_bin(b'=') {
+                if self.match_bin(b'=').is_some() {
                     Token::BangEqual
                 } else {
                     Token::Bang
                 }
             }
             b'=' => {
-                if let Some(_) = self.match_bin(b'=') {
+                if self.match_bin(b'=').is_some() {
                     Token::EqualEqual
                 } else {
                     Token::Equal
                 }
             }
             b'<' => {
-                if let Some(_) = self.match_bin(b'=') {
+                if self.match_bin(b'=').is_some() {
                     Token::LesserEqual
                 } else {
                     Token::Lesser
                 }
             }
             b'>' => {
-                if let Some(_) = self.match_bin(b'=') {
+                if self.match_bin(b'=').is_some() {
                     Token::GreaterEqual
                 } else {
                     Token::Greater
                 }
             }
             b'"' => self.string()?,
-            b'a'..=b'z' | b'_' => self.identifier()?,
-            _ => return Err(ScanError::UnknownToken),
+            c if is_ascii_alphabetic_or_underscore(&c) => self.identifier()?,
+            _ => return Err(ScanErrorKind::UnknownToken),
         };
 
         Result::Ok((tok, self.line, self.cursor - self.start))
@@ -238,37 +223,43 @@ mod scanner {
     fn test_token_no_string() {
         let code = r#"NoString"#;
         let mut scan = Scanner::new(code);
-        assert_eq!(scan.scan_token(), Err(ScanError::UnknownToken));
+        assert_eq!(
+            scan.scan_token(),
+            Ok((Token::Identifier(b"NoString"), 1, 8))
+        );
+        assert_eq!(scan.scan_token(), Err(ScanErrorKind::End));
     }
 
     #[test]
     fn test_token_string() {
         let code = "\"test\"";
         let mut scan = Scanner::new(code);
-        assert_eq!(scan.scan_token(), Ok((Token::String, 1, 6)));
+        assert_eq!(scan.scan_token(), Ok((Token::String(b"test"), 1, 6)));
     }
 
     #[test]
     fn test_unmatched_string() {
         let code = "\"";
         let mut scan = Scanner::new(code);
-        assert_eq!(scan.scan_token(), Err(ScanError::UnmatchedString));
+        assert_eq!(scan.scan_token(), Err(ScanErrorKind::UnmatchedString));
     }
 
     #[test]
     fn test_empty_string() {
         let code = r#""""#;
 
-        println!("{}", code);
         let mut scan = Scanner::new(code);
-        assert_eq!(scan.scan_token(), Ok((Token::String, 1, 2)));
+        assert_eq!(scan.scan_token(), Ok((Token::String(b""), 1, 2)));
     }
     #[test]
     fn test_unmatched_char() {
         let code = r#"e""#;
 
         let mut scan = Scanner::new(code);
-        assert_eq!(scan.scan_token(), Err(ScanError::UnknownToken));
+        assert_eq!(scan.scan_token(), Ok((Token::Identifier(b"e"), 1, 1)));
+        assert_eq!(scan.scan_token(), Err(ScanErrorKind::UnmatchedString));
+        let mut scan = Scanner::new("@");
+        assert_eq!(scan.scan_token(), Err(ScanErrorKind::UnknownToken));
     }
 
     #[test]
@@ -305,13 +296,13 @@ mod scanner {
 
     #[test]
     fn test_scan_tok_real() {
-        let code = r#"if else fun "hello", self print and for let nil loop return while"#;
+        let code = r#"if else fun "hello", self print and for let nil loop return while  x_1"#;
 
         let mut scan = Scanner::new(code);
         assert_eq!(scan.scan_token(), Ok((Token::If, 1, 2)));
         assert_eq!(scan.scan_token(), Ok((Token::Else, 1, 4)));
         assert_eq!(scan.scan_token(), Ok((Token::Fun, 1, 3)));
-        assert_eq!(scan.scan_token(), Ok((Token::String, 1, 7)));
+        assert_eq!(scan.scan_token(), Ok((Token::String(b"hello"), 1, 7)));
         assert_eq!(scan.scan_token(), Ok((Token::Comma, 1, 1)));
         assert_eq!(scan.scan_token(), Ok((Token::TokSelf, 1, 4)));
         assert_eq!(scan.scan_token(), Ok((Token::Print, 1, 5)));
@@ -322,6 +313,7 @@ mod scanner {
         assert_eq!(scan.scan_token(), Ok((Token::Loop, 1, 4)));
         assert_eq!(scan.scan_token(), Ok((Token::Return, 1, 6)));
         assert_eq!(scan.scan_token(), Ok((Token::While, 1, 5)));
+        assert_eq!(scan.scan_token(), Ok((Token::Identifier(b"x_1"), 1, 3)));
     }
 
     #[test]
@@ -5,18 +5,19 @@ use std::fmt::{Display, Formatter};
 
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_until, take_while, take},
-    character::{complete::multispace0, is_alphabetic, is_digit},
-    combinator::value,
-    error::ParseError,
-    number::complete::{double},
+    bytes::complete::{tag, take, take_until, take_while, take_while1},
+    character::complete::{digit1, multispace0},
+    combinator::{cut, peek, value},
+    error::{ErrorKind, ParseError},
+    number::complete::double,
     sequence::delimited,
-    IResult, Offset,
+    AsChar, IResult, Input, Parser as _,
 };
 
 // use nom_locate::LocatedSpan;
 pub type Span<'a> = nom_locate::LocatedSpan<&'a [u8]>;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Token<'a> {
     /// (
@@ -33,6 +34,8 @@ pub enum Token<'a> {
     RightSquare,
     /// ,
     Comma,
+    /// :
+    Colon,
     /// .
     Dot,
     /// -
@@ -64,9 +67,8 @@ pub enum Token<'a> {
     LesserEqual,
 
     // Litterals
-    /// ???
-    //    Identifier(&'a [u8]),
-    IdentifierNoData,
+    /// [a-zA-Z_][a-zA-Z0-9_]*
+    Identifier(&'a [u8]),
     /// "[.]*"
     String(&'a [u8]),
     /// 0-9
@@ -95,6 +97,10 @@ pub enum Token<'a> {
     While,
     /// For
     For,
+    /// break
+    Break,
+    /// continue
+    Continue,
     /// Nil
     Nil,
     /// Return
@@ -105,8 +111,9 @@ pub enum Token<'a> {
     Print,
     /*
     Class,
-    Super
      */
+    /// super
+    Super,
     TokSelf,
 
     SingleComment,
@@ -116,28 +123,260 @@ pub enum Token<'a> {
     EOF,
 }
 
-struct Parser<'a> {
-    current: Token<'a>,
-    previous: Token<'a>,
+impl Display for Token<'_> {
+    /// Write back the lexeme of the token, used in diagnostics.
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Token::LeftParens => "(",
+            Token::RightParens => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftSquare => "[",
+            Token::RightSquare => "]",
+            Token::Comma => ",",
+            Token::Colon => ":",
+            Token::Dot => ".",
+            Token::Minus => "-",
+            Token::Plus => "+",
+            Token::Semicolon => ";",
+            Token::Slash => "/",
+            Token::Star => "*",
+            Token::Bang => "!",
+            Token::BangEqual => "!=",
+            Token::Equal => "=",
+            Token::EqualEqual => "==",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+            Token::Lesser => "<",
+            Token::LesserEqual => "<=",
+            Token::Identifier(ident) => return write!(fmt, "{}", String::from_utf8_lossy(ident)),
+            Token::String(s) => return write!(fmt, "\"{}\"", String::from_utf8_lossy(s)),
+            Token::Number(n) => return write!(fmt, "{}", n),
+            Token::And => "and",
+            Token::Or => "or",
+            Token::Struct => "struct",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Fun => "fun",
+            Token::Loop => "loop",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Nil => "nil",
+            Token::Return => "return",
+            Token::Let => "let",
+            Token::Print => "print",
+            Token::Super => "super",
+            Token::TokSelf => "self",
+            Token::SingleComment => "//",
+            Token::MultiComment => "/* */",
+            Token::Unknown => "unknown",
+            Token::EOF => "end",
+        };
+        write!(fmt, "{}", s)
+    }
 }
 
-// struct TokenPos<'a> {
-//     pub position: Span<'a>,
-//     pub token: Token
-// }
+/// A token and where it was found in the source.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TokenPos<'a> {
+    pub token: Token<'a>,
+    /// Starting at 1.
+    pub line: u32,
+    /// Starting at 1, counted in utf8 characters.
+    pub column: usize,
+    /// Byte offsets of the lexeme in the source.
+    pub start: usize,
+    pub end: usize,
+}
+
+impl<'a> TokenPos<'a> {
+    /// Build a token position from the spans before and after its lexeme.
+    pub fn new(token: Token<'a>, start: Span<'a>, end: Span<'a>) -> TokenPos<'a> {
+        TokenPos {
+            token,
+            line: start.location_line(),
+            column: start.get_utf8_column(),
+            start: start.location_offset(),
+            end: end.location_offset(),
+        }
+    }
+}
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum ScanError {
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScanErrorKind {
     UnknownToken,
     UnmatchedString,
+    UnterminatedComment,
     NumberNotRecognized,
+    /// Only from the hand lexer, [Lexer] ends with `None`.
+    #[cfg(test)]
     End,
 }
 
-enum RloxParseError {
+/// A lexing error and the bytes it covers.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ScanError {
+    pub kind: ScanErrorKind,
+    pub line: u32,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ScanError {
+    fn new(kind: ScanErrorKind, start: Span, end: Span) -> ScanError {
+        ScanError {
+            kind,
+            line: start.location_line(),
+            column: start.get_utf8_column(),
+            start: start.location_offset(),
+            end: end.location_offset(),
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self.kind {
+            ScanErrorKind::UnknownToken => "Unexpected character.",
+            ScanErrorKind::UnmatchedString => "Unterminated string.",
+            ScanErrorKind::UnterminatedComment => "Unterminated comment.",
+            ScanErrorKind::NumberNotRecognized => "Number not recognized.",
+            #[cfg(test)]
+            ScanErrorKind::End => "Unexpected end of input.",
+        }
+    }
+}
+
+impl Display for ScanError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}", self.message())
+    }
+}
+
+/// Iterate over the tokens of a source, skipping whitespaces.
+///
+/// Stops at the end of input, see [Lexer::eof] for the position of the end.
+pub struct Lexer<'a> {
+    code: Span<'a>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(code: &'a [u8]) -> Lexer<'a> {
+        Lexer {
+            code: Span::new(code),
+        }
+    }
+
+    /// An [Token::EOF] located at the current position.
+    pub fn eof(&self) -> TokenPos<'a> {
+        TokenPos::new(Token::EOF, self.code, self.code)
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<TokenPos<'a>, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, _) = multispace0::<_, nom::error::Error<_>>(self.code).ok()?;
+        self.code = start;
+        if start.fragment().is_empty() {
+            return None;
+        }
+        match scan_token(start) {
+            Ok((next, pos)) => {
+                self.code = next;
+                if pos.token == Token::Unknown {
+                    let end = start.take_from(pos.end - pos.start);
+                    return Some(Err(ScanError::new(ScanErrorKind::UnknownToken, start, end)));
+                }
+                Some(Ok(pos))
+            }
+            Err(err) => {
+                let fragment = start.fragment();
+                let kind = if fragment.starts_with(b"\"") {
+                    ScanErrorKind::UnmatchedString
+                } else if fragment.starts_with(b"/*") {
+                    ScanErrorKind::UnterminatedComment
+                } else if fragment.first().is_some_and(u8::is_ascii_digit) {
+                    ScanErrorKind::NumberNotRecognized
+                } else {
+                    ScanErrorKind::UnknownToken
+                };
+                self.code = match (kind, err) {
+                    // Resume right after the malformed number.
+                    (ScanErrorKind::NumberNotRecognized, nom::Err::Failure(e)) => e.input,
+                    // Unterminated strings and comments eat the rest of the input.
+                    (ScanErrorKind::UnmatchedString | ScanErrorKind::UnterminatedComment, _) => {
+                        start.take_from(fragment.len())
+                    }
+                    _ => start.take_from(1),
+                };
+                Some(Err(ScanError::new(kind, start, self.code)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RloxParseError {
     TooManyConstant,
     UnclosedParens,
     ExpectedExpression,
+    ExpectedSemicolonAfterValue,
+    ExpectedSemicolonAfterExpression,
+    ExpectedSemicolonAfterVariable,
+    ExpectedVariableName,
+    InvalidAssignmentTarget,
+    ExpectedBlockEnd,
+    AlreadyDeclared,
+    ReadLocalInInitializer,
+    TooManyLocals,
+    JumpTooLarge,
+    LoopTooLarge,
+    ExpectedParensAfterIf,
+    ExpectedParensAfterWhile,
+    ExpectedParensAfterCondition,
+    ExpectedParensAfterFor,
+    ExpectedSemicolonAfterCondition,
+    ExpectedParensAfterClauses,
+    ExpectedBraceAfterLoop,
+    ExpectedSemicolonAfterBreak,
+    ExpectedSemicolonAfterContinue,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    ExpectedFunctionName,
+    ExpectedParameterName,
+    ExpectedParensAfterFunctionName,
+    ExpectedParensAfterParameters,
+    ExpectedBraceBeforeFunctionBody,
+    ExpectedParensAfterArguments,
+    ExpectedSemicolonAfterReturn,
+    TooManyParameters,
+    TooManyArguments,
+    ReturnFromScript,
+    TooManyUpvalues,
+    ExpectedStructName,
+    ExpectedBraceBeforeStructBody,
+    ExpectedBraceAfterStructBody,
+    ExpectedMethodName,
+    ExpectedPropertyName,
+    SelfOutsideStruct,
+    ReturnValueFromInitializer,
+    ExpectedSuperclassName,
+    InheritFromItself,
+    SuperOutsideStruct,
+    SuperWithoutSuperclass,
+    ExpectedDotAfterSuper,
+    ExpectedSuperclassMethodName,
+    ExpectedSquareAfterElements,
+    ExpectedSquareAfterIndex,
+    TooManyElements,
+    ExpectedColonAfterKey,
+    ExpectedBraceAfterEntries,
+    TooManyEntries,
 }
 
 impl From<RloxParseError> for &'static str {
@@ -146,22 +385,86 @@ impl From<RloxParseError> for &'static str {
             RloxParseError::TooManyConstant => "Too many constants in one chunk.",
             RloxParseError::UnclosedParens => "Expect ')' after expression.",
             RloxParseError::ExpectedExpression => "Expect expression.",
+            RloxParseError::ExpectedSemicolonAfterValue => "Expect ';' after value.",
+            RloxParseError::ExpectedSemicolonAfterExpression => "Expect ';' after expression.",
+            RloxParseError::ExpectedSemicolonAfterVariable => {
+                "Expect ';' after variable declaration."
+            }
+            RloxParseError::ExpectedVariableName => "Expect variable name.",
+            RloxParseError::InvalidAssignmentTarget => "Invalid assignment target.",
+            RloxParseError::ExpectedBlockEnd => "Expect '}' after block.",
+            RloxParseError::AlreadyDeclared => "Already a variable with this name in this scope.",
+            RloxParseError::ReadLocalInInitializer => {
+                "Can't read local variable in its own initializer."
+            }
+            RloxParseError::TooManyLocals => "Too many local variables in function.",
+            RloxParseError::JumpTooLarge => "Too much code to jump over.",
+            RloxParseError::LoopTooLarge => "Loop body too large.",
+            RloxParseError::ExpectedParensAfterIf => "Expect '(' after 'if'.",
+            RloxParseError::ExpectedParensAfterWhile => "Expect '(' after 'while'.",
+            RloxParseError::ExpectedParensAfterCondition => "Expect ')' after condition.",
+            RloxParseError::ExpectedParensAfterFor => "Expect '(' after 'for'.",
+            RloxParseError::ExpectedSemicolonAfterCondition => "Expect ';' after loop condition.",
+            RloxParseError::ExpectedParensAfterClauses => "Expect ')' after for clauses.",
+            RloxParseError::ExpectedBraceAfterLoop => "Expect '{' after 'loop'.",
+            RloxParseError::ExpectedSemicolonAfterBreak => "Expect ';' after 'break'.",
+            RloxParseError::ExpectedSemicolonAfterContinue => "Expect ';' after 'continue'.",
+            RloxParseError::BreakOutsideLoop => "Can't use 'break' outside of a loop.",
+            RloxParseError::ContinueOutsideLoop => "Can't use 'continue' outside of a loop.",
+            RloxParseError::ExpectedFunctionName => "Expect function name.",
+            RloxParseError::ExpectedParameterName => "Expect parameter name.",
+            RloxParseError::ExpectedParensAfterFunctionName => "Expect '(' after function name.",
+            RloxParseError::ExpectedParensAfterParameters => "Expect ')' after parameters.",
+            RloxParseError::ExpectedBraceBeforeFunctionBody => "Expect '{' before function body.",
+            RloxParseError::ExpectedParensAfterArguments => "Expect ')' after arguments.",
+            RloxParseError::ExpectedSemicolonAfterReturn => "Expect ';' after return value.",
+            RloxParseError::TooManyParameters => "Can't have more than 255 parameters.",
+            RloxParseError::TooManyArguments => "Can't have more than 255 arguments.",
+            RloxParseError::ReturnFromScript => "Can't return from top-level code.",
+            RloxParseError::TooManyUpvalues => "Too many closure variables in function.",
+            RloxParseError::ExpectedStructName => "Expect struct name.",
+            RloxParseError::ExpectedBraceBeforeStructBody => "Expect '{' before struct body.",
+            RloxParseError::ExpectedBraceAfterStructBody => "Expect '}' after struct body.",
+            RloxParseError::ExpectedMethodName => "Expect method name.",
+            RloxParseError::ExpectedPropertyName => "Expect property name after '.'.",
+            RloxParseError::SelfOutsideStruct => "Can't use 'self' outside of a struct.",
+            RloxParseError::ReturnValueFromInitializer => {
+                "Can't return a value from an initializer."
+            }
+            RloxParseError::ExpectedSuperclassName => "Expect superclass name.",
+            RloxParseError::InheritFromItself => "A struct can't inherit from itself.",
+            RloxParseError::SuperOutsideStruct => "Can't use 'super' outside of a struct.",
+            RloxParseError::SuperWithoutSuperclass => {
+                "Can't use 'super' in a struct with no superclass."
+            }
+            RloxParseError::ExpectedDotAfterSuper => "Expect '.' after 'super'.",
+            RloxParseError::ExpectedSuperclassMethodName => "Expect superclass method name.",
+            RloxParseError::ExpectedSquareAfterElements => "Expect ']' after array elements.",
+            RloxParseError::ExpectedSquareAfterIndex => "Expect ']' after index.",
+            RloxParseError::TooManyElements => {
+                "Can't have more than 255 elements in an array literal."
+            }
+            RloxParseError::ExpectedColonAfterKey => "Expect ':' after map key.",
+            RloxParseError::ExpectedBraceAfterEntries => "Expect '}' after map entries.",
+            RloxParseError::TooManyEntries => "Can't have more than 255 entries in a map literal.",
         }
     }
 }
 
 impl Display for RloxParseError {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
-        todo!()
+        let msg: &'static str = (*self).into();
+        write!(fmt, "{}", msg)
     }
 }
 
-pub fn keyword_or_ident(input: &[u8]) -> Token {
+pub fn keyword_or_ident(input: &[u8]) -> Token<'_> {
     match input {
         b"" => Token::EOF,
         b"false" => Token::False,
         b"true" => Token::True,
         b"self" => Token::TokSelf,
+        b"super" => Token::Super,
         b"struct" => Token::Struct,
         b"return" => Token::Return,
         b"loop" => Token::Loop,
@@ -172,18 +475,19 @@ pub fn keyword_or_ident(input: &[u8]) -> Token {
         b"and" => Token::And,
         b"for" => Token::For,
         b"while" => Token::While,
+        b"break" => Token::Break,
+        b"continue" => Token::Continue,
         b"if" => Token::If,
         b"else" => Token::Else,
         // TODO use Option<T>?
         b"nil" => Token::Nil,
         b"let" => Token::Let,
-        _ => Token::IdentifierNoData,
-        //dent => Token::Identifier(ident),
+        ident => Token::Identifier(ident),
     }
 }
 
-pub fn scan_token(input: Span) -> IResult<Span, Token> {
-    let inner = alt((
+pub fn scan_token(input: Span) -> IResult<Span, TokenPos> {
+    let mut inner = alt((
         comments_multi_line,
         comments_single_line,
         numbers,
@@ -194,57 +498,85 @@ pub fn scan_token(input: Span) -> IResult<Span, Token> {
         unknown,
     ));
 
-    delimited(multispace0, inner, multispace0)(input)
+    let (start, _) = multispace0(input)?;
+    let (end, token) = inner.parse(start)?;
+    let (input, _) = multispace0(end)?;
+    Ok((input, TokenPos::new(token, start, end)))
 }
 
-/// TODO: Add lines numbers
-/// Eats // or */
+/// Eats /* */, fails without backtracking when unterminated.
 #[inline]
 pub fn comments_multi_line(input: Span) -> IResult<Span, Token> {
-    let (input, _) = tag("/*")(input)?;
-    let (input, _) = take_until("*/")(input)?;
-    let (input, _) = tag("*/")(input)?;
+    let (input, _) = tag("/*").parse(input)?;
+    let (input, _) = cut(take_until("*/")).parse(input)?;
+    let (input, _) = tag("*/").parse(input)?;
     Ok((input, Token::MultiComment))
 }
 
 #[inline]
 pub fn comments_single_line(input: Span) -> IResult<Span, Token> {
-    let (input, _) = tag("//")(input)?;
-    let (input, _) = is_not("\n")(input)?;
+    let (input, _) = tag("//").parse(input)?;
+    let (input, _) = take_while(|c| c != b'\n').parse(input)?;
     Ok((input, Token::SingleComment))
 }
 
 /// Taken from nom_recipes
 /// A combinator that takes a parser `inner` and produces a parser that also consumes both leading and
 /// trailing whitespace, returning the output of `inner`.
+#[allow(dead_code)]
 #[inline]
-pub fn start_end_trailling_spaces<'a, F: 'a, O, E: ParseError<&'a str>>(
+pub fn start_end_trailling_spaces<'a, F, O, E: ParseError<&'a str>>(
     inner: F,
-) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
+) -> impl nom::Parser<&'a str, Output = O, Error = E>
 where
-    F: Fn(&'a str) -> IResult<&'a str, O, E>,
+    F: nom::Parser<&'a str, Output = O, Error = E> + 'a,
 {
     delimited(multispace0, inner, multispace0)
 }
 
-// TODO count lines.
+/// Fails without backtracking when unterminated.
 #[inline]
 pub fn string(input: Span) -> IResult<Span, Token> {
-    let (input, _) = tag("\"")(input)?;
-    let (input, string_raw) = take_until("\"")(input)?;
-    let (input, _) = tag("\"")(input)?;
+    let (input, _) = tag("\"").parse(input)?;
+    let (input, string_raw) = cut(take_until("\"")).parse(input)?;
+    let (input, _) = tag("\"").parse(input)?;
     Ok((input, Token::String(&string_raw)))
 }
 
+/// Fails without backtracking on an exponent without digits like `1e`, the
+/// error input is right after the malformed lexeme.
 #[inline]
 pub fn numbers(input: Span) -> IResult<Span, Token> {
-    let (input, number) = double(input)?;
+    // `double` also eats signs and `inf`/`nan`, leave those to operators and identifiers.
+    let (input, _) = peek(digit1).parse(input)?;
+    let (input, number) = match double::<_, nom::error::Error<_>>(input) {
+        Ok(ok) => ok,
+        Err(nom::Err::Failure(_)) => {
+            // Cover the whole lexeme, like `2.5e+` or `2else`.
+            let fragment = input.fragment();
+            let len = (0..fragment.len())
+                .take_while(|&i| match fragment[i] {
+                    b'+' | b'-' => i > 0 && matches!(fragment[i - 1], b'e' | b'E'),
+                    c => c == b'.' || underscore_alphadigit(c),
+                })
+                .count();
+            let rest = input.take_from(len);
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                rest,
+                ErrorKind::Float,
+            )));
+        }
+        Err(e) => return Err(e),
+    };
     Ok((input, Token::Number(number)))
 }
 
+fn underscore_alphadigit(c: u8) -> bool {
+    c.is_alpha() || c.is_dec_digit() || c == b'_'
+}
+
 fn keywords_and_identifiers(input: Span) -> IResult<Span, Token> {
-    let underscore_alphadigit = |c| is_alphabetic(c) || is_digit(c) || c == b'_';
-    let (input, ident) = take_while(underscore_alphadigit)(input)?;
+    let (input, ident) = take_while1(underscore_alphadigit).parse(input)?;
     let token = keyword_or_ident(ident.fragment());
     //.map_err(
     //     //manage userToken
@@ -263,31 +595,32 @@ fn delimiters(input: Span) -> IResult<Span, Token> {
         right_brace,
         left_brace,
         right_brace,
-    ))(input)
+    ))
+    .parse(input)
 }
 
 fn left_parens(input: Span) -> IResult<Span, Token> {
-    value(Token::LeftParens, tag("("))(input)
+    value(Token::LeftParens, tag("(")).parse(input)
 }
 
 fn right_parens(input: Span) -> IResult<Span, Token> {
-    value(Token::RightParens, tag(")"))(input)
+    value(Token::RightParens, tag(")")).parse(input)
 }
 
 fn left_brace(input: Span) -> IResult<Span, Token> {
-    value(Token::LeftBrace, tag("{"))(input)
+    value(Token::LeftBrace, tag("{")).parse(input)
 }
 
 fn right_brace(input: Span) -> IResult<Span, Token> {
-    value(Token::RightBrace, tag("}"))(input)
+    value(Token::RightBrace, tag("}")).parse(input)
 }
 
 fn left_square(input: Span) -> IResult<Span, Token> {
-    value(Token::LeftSquare, tag("["))(input)
+    value(Token::LeftSquare, tag("[")).parse(input)
 }
 
 fn right_square(input: Span) -> IResult<Span, Token> {
-    value(Token::RightSquare, tag("]"))(input)
+    value(Token::RightSquare, tag("]")).parse(input)
 }
 
 /* operators */
@@ -296,6 +629,7 @@ fn operators(input: Span) -> IResult<Span, Token> {
     alt((
         semicolon,
         comma,
+        colon,
         star,
         lesser_equal,
         greater_equal,
@@ -309,74 +643,79 @@ fn operators(input: Span) -> IResult<Span, Token> {
         dot,
         bang,
         equal,
-    ))(input)
+    ))
+    .parse(input)
 }
 
 fn semicolon(input: Span) -> IResult<Span, Token> {
-    value(Token::Semicolon, tag(";"))(input)
+    value(Token::Semicolon, tag(";")).parse(input)
 }
 
 fn comma(input: Span) -> IResult<Span, Token> {
-    value(Token::Comma, tag(","))(input)
+    value(Token::Comma, tag(",")).parse(input)
+}
+
+fn colon(input: Span) -> IResult<Span, Token> {
+    value(Token::Colon, tag(":")).parse(input)
 }
 
 fn bang_equal(input: Span) -> IResult<Span, Token> {
-    value(Token::BangEqual, tag("!="))(input)
+    value(Token::BangEqual, tag("!=")).parse(input)
 }
 
 fn equal_equal(input: Span) -> IResult<Span, Token> {
-    value(Token::EqualEqual, tag("=="))(input)
+    value(Token::EqualEqual, tag("==")).parse(input)
 }
 
 fn greater_equal(input: Span) -> IResult<Span, Token> {
-    value(Token::GreaterEqual, tag(">="))(input)
+    value(Token::GreaterEqual, tag(">=")).parse(input)
 }
 
 fn lesser_equal(input: Span) -> IResult<Span, Token> {
-    value(Token::LesserEqual, tag("<="))(input)
+    value(Token::LesserEqual, tag("<=")).parse(input)
 }
 
 /* Operators unary only.
 If you add something like += please move them to the alt version !
 */
 fn dot(input: Span) -> IResult<Span, Token> {
-    value(Token::Dot, tag("."))(input)
+    value(Token::Dot, tag(".")).parse(input)
 }
 
 fn minus(input: Span) -> IResult<Span, Token> {
-    value(Token::Minus, tag("-"))(input)
+    value(Token::Minus, tag("-")).parse(input)
 }
 
 fn plus(input: Span) -> IResult<Span, Token> {
-    value(Token::Plus, tag("+"))(input)
+    value(Token::Plus, tag("+")).parse(input)
 }
 
 fn slash(input: Span) -> IResult<Span, Token> {
-    value(Token::Slash, tag("/"))(input)
+    value(Token::Slash, tag("/")).parse(input)
 }
 
 fn star(input: Span) -> IResult<Span, Token> {
-    value(Token::Star, tag("*"))(input)
+    value(Token::Star, tag("*")).parse(input)
 }
 
 fn lesser(input: Span) -> IResult<Span, Token> {
-    value(Token::Lesser, tag("<"))(input)
+    value(Token::Lesser, tag("<")).parse(input)
 }
 
 fn greater(input: Span) -> IResult<Span, Token> {
-    value(Token::Greater, tag(">"))(input)
+    value(Token::Greater, tag(">")).parse(input)
 }
 
 fn equal(input: Span) -> IResult<Span, Token> {
-    value(Token::Equal, tag("="))(input)
+    value(Token::Equal, tag("=")).parse(input)
 }
 
 fn bang(input: Span) -> IResult<Span, Token> {
-    value(Token::Bang, tag("!"))(input)
+    value(Token::Bang, tag("!")).parse(input)
 }
 
 fn unknown(input: Span) -> IResult<Span, Token> {
-    nom::combinator::map(take(1usize), |_| Token::Unknown)(input)
+    nom::combinator::map(take(1usize), |_| Token::Unknown).parse(input)
 }
 
 #[cfg(test)]
@@ -387,12 +726,13 @@ mod test_lexer {
     fn assert_token_span<'a>(
         code: Span<'a>,
         expected: Token<'a>,
-        offset: usize,
+        _offset: usize,
         line: u32,
     ) -> IResult<Span<'a>, Token<'a>> {
         let span = code;
         let ret = scan_token(span);
         let (span, tok) = ret?;
+        let tok = tok.token;
         assert_eq!(tok, expected, "Token didn't match.");
         // todo debug space eating.
         //assert_eq!(span.location_offset(), offset, "Offset not equals.");
@@ -433,7 +773,7 @@ mod test_lexer {
 
     #[test]
     fn test_scan_tok_real() {
-        let code = br#"if else fun, self print and for let nil loop return while"#;
+        let code = br#"if else fun, self print and for let nil loop return while break continue"#;
         let expected = [
             (Token::If, 3, 1),
             // need rework
@@ -450,6 +790,8 @@ mod test_lexer {
             (Token::Loop, 48, 1),
             (Token::Return, 53, 1),
             (Token::While, 62, 1),
+            (Token::Break, 68, 1),
+            (Token::Continue, 77, 1),
         ];
 
         let mut code = Span::new(code);
@@ -480,11 +822,91 @@ mod test_lexer {
         }
     }
 
+    #[test]
+    fn test_map_literal() {
+        let code = br#"{"k": 1}"#;
+        let expected = [
+            (Token::LeftBrace, 1, 1),
+            (Token::String(b"k"), 3, 1),
+            (Token::Colon, 1, 1),
+            (Token::Number(1.), 1, 1),
+            (Token::RightBrace, 1, 1),
+        ];
+
+        let mut code = Span::new(code);
+        for (token, offset, line) in expected {
+            code = assert_token_span(code, token, offset, line)
+                .expect("Should have been parsed.")
+                .0;
+        }
+    }
+
+    #[test]
+    fn test_number_no_sign() {
+        let code = br#"1+2-3"#;
+        let expected = [
+            (Token::Number(1.), 1, 1),
+            (Token::Plus, 1, 1),
+            (Token::Number(2.), 1, 1),
+            (Token::Minus, 1, 1),
+            (Token::Number(3.), 1, 1),
+        ];
+
+        let mut code = Span::new(code);
+        for (token, offset, line) in expected {
+            code = assert_token_span(code, token, offset, line)
+                .expect("Should have been parsed.")
+                .0;
+        }
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let code = br#"foo _bar Baz1 iffy"#;
+        let expected = [
+            (Token::Identifier(b"foo"), 3, 1),
+            (Token::Identifier(b"_bar"), 8, 1),
+            (Token::Identifier(b"Baz1"), 13, 1),
+            (Token::Identifier(b"iffy"), 18, 1),
+        ];
+
+        let mut code = Span::new(code);
+        for (token, offset, line) in expected {
+            code = assert_token_span(code, token, offset, line)
+                .expect("Should have been parsed.")
+                .0;
+        }
+    }
+
+    #[test]
+    fn test_token_position() {
+        let code = Span::new(b"let x\n  = \"\xc3\xa9t\xc3\xa9\" + 12;");
+        let expected = [
+            (Token::Let, 1, 1, 0..3),
+            (Token::Identifier(b"x"), 1, 5, 4..5),
+            (Token::Equal, 2, 3, 8..9),
+            (Token::String("été".as_bytes()), 2, 5, 10..17),
+            (Token::Plus, 2, 11, 18..19),
+            (Token::Number(12.), 2, 13, 20..22),
+            (Token::Semicolon, 2, 15, 22..23),
+        ];
+
+        let mut code = code;
+        for (token, line, column, range) in expected {
+            let (next, pos) = scan_token(code).expect("Should have been parsed.");
+            assert_eq!(pos.token, token);
+            assert_eq!(pos.line, line, "Line of {:?}", token);
+            assert_eq!(pos.column, column, "Column of {:?}", token);
+            assert_eq!(pos.start..pos.end, range, "Range of {:?}", token);
+            code = next;
+        }
+    }
+
     #[test]
     fn test_token_no_string() {
         let code = br#"NoString"#;
         let code = Span::new(code);
-        let ret = scan_token(code);
+        let _ret = scan_token(code);
         assert!(string(code).is_err());
         // assert_eq!(ret, Err(ScanError::UnknownToken));
     }
@@ -499,10 +921,63 @@ mod test_lexer {
     #[test]
     fn test_unmatched_string() {
         let code = b"\"";
-        let code = Span::new(code);
-        let ret = scan_token(code);
-        assert!(string(code).is_err());
-        // assert_eq!(ret, Err(ScanError::UnmatchedString));
+        assert!(string(Span::new(code)).is_err());
+        let err = Lexer::new(code).next().unwrap().unwrap_err();
+        assert_eq!(err.kind, ScanErrorKind::UnmatchedString);
+    }
+
+    #[test]
+    fn test_lexer_end() {
+        let tokens: Vec<_> = Lexer::new(b" 1 // one\n+ 2 \n ")
+            .map(|t| t.unwrap().token)
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Number(1.),
+                Token::SingleComment,
+                Token::Plus,
+                Token::Number(2.)
+            ]
+        );
+        assert_eq!(Lexer::new(b"").next(), None);
+        assert_eq!(
+            Lexer::new(b"//").next().unwrap().unwrap().token,
+            Token::SingleComment
+        );
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let mut lexer = Lexer::new(b"1 @ 2\n  /* never closed\n");
+        assert_eq!(lexer.next().unwrap().unwrap().token, Token::Number(1.));
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, ScanErrorKind::UnknownToken);
+        assert_eq!((err.line, err.column, err.start, err.end), (1, 3, 2, 3));
+        assert_eq!(lexer.next().unwrap().unwrap().token, Token::Number(2.));
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, ScanErrorKind::UnterminatedComment);
+        assert_eq!((err.line, err.column, err.start, err.end), (2, 3, 8, 24));
+        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.eof().line, 3);
+
+        // A malformed number is skipped, and scanning goes on after it.
+        let mut lexer = Lexer::new(b"1e; 2.5E+ 3else");
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, ScanErrorKind::NumberNotRecognized);
+        assert_eq!((err.start, err.end), (0, 2));
+        assert_eq!(lexer.next().unwrap().unwrap().token, Token::Semicolon);
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(
+            (err.kind, err.start, err.end),
+            (ScanErrorKind::NumberNotRecognized, 4, 9)
+        );
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(
+            (err.kind, err.start, err.end),
+            (ScanErrorKind::NumberNotRecognized, 10, 15)
+        );
+        assert_eq!(lexer.next(), None);
     }
 
     #[test]
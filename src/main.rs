@@ -1,35 +1,164 @@
 use core::fmt;
 use std::{env::args, path::Path};
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::collections::HashMap;
 
-// Should compile but not used now.
-//mod hand_lexer;
+// Not used by the compiler, only built to run its tests.
+#[cfg(test)]
+mod hand_lexer;
 mod lexer;
 mod compiler;
+mod diagnostic;
+mod native;
+mod object;
+mod value;
 
-type Value = f64;
+use lexer::RloxParseError;
+use object::{
+    BoundMethod, Class, Closure, Function, Heap, Instance, Map, MapKey, Obj, ObjRef, Upvalue,
+};
+use value::Value;
 
-#[derive(Debug, PartialEq, Eq)]
+/// A 24 bits little endian index in the constants, three bytes keep the
+/// opcodes carrying it on four bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Index([u8; 3]);
+
+impl Index {
+    const MAX: usize = 0xFF_FFFF;
+
+    /// None past [Index::MAX].
+    fn new(idx: usize) -> Option<Index> {
+        if idx > Index::MAX {
+            return None;
+        }
+        let [a, b, c, _] = (idx as u32).to_le_bytes();
+        Some(Index([a, b, c]))
+    }
+
+    fn get(self) -> usize {
+        let [a, b, c] = self.0;
+        u32::from_le_bytes([a, b, c, 0]) as usize
+    }
+}
+
+impl From<u8> for Index {
+    fn from(idx: u8) -> Index {
+        Index([idx, 0, 0])
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum Opcode {
     Return,
+    Print,
+    Pop,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
     Negate,
     Add,
     Sub,
     Mul,
     Div,
     Constant(u8),
+    // For constants past the first 256.
+    ConstantLong(Index),
+    DefineGlobal(Index),
+    GetGlobal(Index),
+    SetGlobal(Index),
+    GetLocal(u8),
+    SetLocal(u8),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    // Move the local on top of the stack to the heap, for the closures capturing it.
+    CloseUpvalue,
+    // Wrap the function constant in a closure capturing its upvalues.
+    Closure(Index),
+    Class(Index),
+    GetProperty(Index),
+    SetProperty(Index),
+    // Attach the closure on top of the stack to the struct below it.
+    Method(Index),
+    // Call a method by name, with a number of arguments. Names past the
+    // first 256 constants use GetProperty and Call instead.
+    Invoke(u8, u8),
+    // Copy the methods of the superclass below the struct on top of the stack.
+    Inherit,
+    // Like GetProperty and Invoke, looking up the superclass on top of the stack.
+    GetSuper(Index),
+    SuperInvoke(u8, u8),
+    // Collect that many values of the stack in an array.
+    Array(u8),
+    // Collect that many key value pairs of the stack in a map.
+    Map(u8),
+    GetIndex,
+    SetIndex,
+    // Jumps are relative to their own offset in the chunk.
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    // Number of arguments, the callee is below them on the stack.
+    Call(u8),
     Litteral(u16), // Store directly value
 }
 
 impl std::fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
+            Opcode::Nil => "NIL",
+            Opcode::True => "TRUE",
+            Opcode::False => "FALSE",
+            Opcode::Not => "NOT",
+            Opcode::Equal => "EQUAL",
+            Opcode::Greater => "GREATER",
+            Opcode::Less => "LESS",
             Opcode::Negate => "NEGATE",
             Opcode::Return => "RETURN",
+            Opcode::Print => "PRINT",
+            Opcode::Pop => "POP",
+            Opcode::CloseUpvalue => "CLOSE_UPVALUE",
+            Opcode::Inherit => "INHERIT",
+            Opcode::GetIndex => "GET_INDEX",
+            Opcode::SetIndex => "SET_INDEX",
             Opcode::Constant(c) => {
                 write!(f, "CONSTANT {}", c)?;
                 return fmt::Result::Ok(());
             }
+            Opcode::ConstantLong(c) => return write!(f, "CONSTANT_LONG {}", c),
+            Opcode::DefineGlobal(c) => return write!(f, "DEFINE_GLOBAL {}", c),
+            Opcode::GetGlobal(c) => return write!(f, "GET_GLOBAL {}", c),
+            Opcode::SetGlobal(c) => return write!(f, "SET_GLOBAL {}", c),
+            Opcode::GetLocal(slot) => return write!(f, "GET_LOCAL {}", slot),
+            Opcode::SetLocal(slot) => return write!(f, "SET_LOCAL {}", slot),
+            Opcode::GetUpvalue(slot) => return write!(f, "GET_UPVALUE {}", slot),
+            Opcode::SetUpvalue(slot) => return write!(f, "SET_UPVALUE {}", slot),
+            Opcode::Closure(c) => return write!(f, "CLOSURE {}", c),
+            Opcode::Class(c) => return write!(f, "CLASS {}", c),
+            Opcode::GetProperty(c) => return write!(f, "GET_PROPERTY {}", c),
+            Opcode::SetProperty(c) => return write!(f, "SET_PROPERTY {}", c),
+            Opcode::Method(c) => return write!(f, "METHOD {}", c),
+            Opcode::Invoke(c, count) => return write!(f, "INVOKE ({} args) {}", count, c),
+            Opcode::GetSuper(c) => return write!(f, "GET_SUPER {}", c),
+            Opcode::Array(count) => return write!(f, "ARRAY {}", count),
+            Opcode::Map(count) => return write!(f, "MAP {}", count),
+            Opcode::SuperInvoke(c, count) => {
+                return write!(f, "SUPER_INVOKE ({} args) {}", count, c)
+            }
+            Opcode::Jump(offset) => return write!(f, "JUMP {}", offset),
+            Opcode::JumpIfFalse(offset) => return write!(f, "JUMP_IF_FALSE {}", offset),
+            Opcode::Loop(offset) => return write!(f, "LOOP {}", offset),
+            Opcode::Call(count) => return write!(f, "CALL {}", count),
             Opcode::Litteral(v) => {
                 write!(f, "LITERRAL {}", v)?;
                 return fmt::Result::Ok(());
@@ -48,7 +177,7 @@ struct Chunk {
     // Well a vec is the direct translation of the "growable code zone" in the book.
     code: Vec<Opcode>,
     values: Vec<Value>,
-    lines: Vec<(u8, u16)>, // repeat + lines
+    lines: Vec<(u8, u32)>, // repeat + lines
 }
 
 impl Chunk {
@@ -60,29 +189,53 @@ impl Chunk {
         }
     }
 
-    fn write_value(&mut self, v: Value) -> u8 {
+    /// Add a constant, returning its index.
+    fn write_value(&mut self, v: Value) -> usize {
         self.values.push(v);
-        (self.values.len() - 1) as u8
+        self.values.len() - 1
     }
 
     // Write an opcode, one at a time.
-    fn write_opcode(&mut self, op: Opcode, line: u16) {
+    fn write_opcode(&mut self, op: Opcode, line: u32) {
         self.code.push(op);
         match self.lines.last_mut() {
-            Some((repeat, last)) if *last == line => {
+            Some((repeat, last)) if *last == line && *repeat < u8::MAX => {
                 *repeat += 1;
             }
             _ => self.lines.push((1, line)),
         }
     }
 
+    /// Point the jump at `at` to the next opcode written.
+    fn patch_jump(&mut self, at: usize) -> Result<(), RloxParseError> {
+        let offset =
+            u16::try_from(self.code.len() - at).map_err(|_| RloxParseError::JumpTooLarge)?;
+        match &mut self.code[at] {
+            Opcode::Jump(o) | Opcode::JumpIfFalse(o) => *o = offset,
+            op => unreachable!("Patching {} which is not a jump.", op),
+        }
+        Ok(())
+    }
+
     // Disassemble a chunck and dump it.
-    fn dissemble(&self, name: &str) -> String {
-        format!("=== {} ===\n{}========", name, self)
+    #[cfg_attr(not(feature = "debug_print_code"), allow(dead_code))]
+    fn dissemble(&self, name: &str, heap: &Heap) -> String {
+        format!("=== {} ===\n{}========", name, self.display(heap))
+    }
+
+    #[cfg_attr(not(feature = "debug_print_code"), allow(dead_code))]
+    fn display<'a>(&'a self, heap: &'a Heap) -> ChunkDisplay<'a> {
+        ChunkDisplay { chunk: self, heap }
     }
 }
 
-fn get_line(lines: &[(u8, u16)], idx: usize) -> Option<u16> {
+#[cfg_attr(not(feature = "debug_print_code"), allow(dead_code))]
+struct ChunkDisplay<'a> {
+    chunk: &'a Chunk,
+    heap: &'a Heap,
+}
+
+fn get_line(lines: &[(u8, u32)], idx: usize) -> Option<u32> {
     // Assertion : lines.iter().map(|(r, _), r).sum() == idx
     // partial sum of rep >= idx.
     let mut count = 0usize;
@@ -96,14 +249,44 @@ fn get_line(lines: &[(u8, u16)], idx: usize) -> Option<u16> {
     None
 }
 
-impl std::fmt::Display for Chunk {
+impl std::fmt::Display for ChunkDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        assert!(self.lines.len() <= self.code.len());
-        for (offset, op) in self.code.iter().enumerate() {
-            let line = get_line(&self.lines, offset).unwrap();
+        let chunk = self.chunk;
+        assert!(chunk.lines.len() <= chunk.code.len());
+        for (offset, op) in chunk.code.iter().enumerate() {
+            let line = get_line(&chunk.lines, offset).unwrap();
             write!(f, "{:04}:ln {} ", offset, line)?;
             match op {
-                &Opcode::Constant(i) => write!(f, "{} {}", op, self.values[i as usize])?,
+                &Opcode::Constant(i) | &Opcode::Invoke(i, _) | &Opcode::SuperInvoke(i, _) => {
+                    write!(f, "{} {}", op, chunk.values[i as usize].display(self.heap))?
+                }
+                &Opcode::ConstantLong(i)
+                | &Opcode::DefineGlobal(i)
+                | &Opcode::GetGlobal(i)
+                | &Opcode::SetGlobal(i)
+                | &Opcode::Class(i)
+                | &Opcode::GetProperty(i)
+                | &Opcode::SetProperty(i)
+                | &Opcode::Method(i)
+                | &Opcode::GetSuper(i) => {
+                    write!(f, "{} {}", op, chunk.values[i.get()].display(self.heap))?
+                }
+                &Opcode::Closure(i) => {
+                    let function = chunk.values[i.get()];
+                    write!(f, "{} {}", op, function.display(self.heap))?;
+                    let captures = match function {
+                        Value::Obj(r) => self.heap.as_function(r).map(|f| &f.upvalues[..]),
+                        _ => None,
+                    };
+                    for capture in captures.unwrap_or_default() {
+                        let kind = if capture.local { "local" } else { "upvalue" };
+                        write!(f, " {} {}", kind, capture.index)?;
+                    }
+                }
+                &Opcode::Jump(o) | &Opcode::JumpIfFalse(o) => {
+                    write!(f, "{} -> {:04}", op, offset + o as usize)?
+                }
+                &Opcode::Loop(o) => write!(f, "{} -> {:04}", op, offset - o as usize)?,
                 _ => write!(f, "{}", op)?,
             }
             writeln!(f)?;
@@ -112,111 +295,894 @@ impl std::fmt::Display for Chunk {
     }
 }
 
+const ADD_OPERANDS: &str = "Operands must be two numbers or two strings.";
+const CALLABLES: &str = "Can only call functions and structs.";
+const INDEXABLES: &str = "Only arrays and maps can be indexed.";
+
+/// Where `print` writes, stdout outside of tests.
+struct Output(Box<dyn Write>);
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// Default limit of nested calls.
+const FRAMES_MAX: usize = 64;
+
+/// A function being executed.
+#[derive(Debug)]
+struct CallFrame {
+    closure: ObjRef,
+    // The function of the closure, to find the chunk without an indirection.
+    function: ObjRef,
+    // Where to resume the caller, only up to date when the frame is not the last.
+    ip: usize,
+    // Stack index of the slot zero of the function.
+    slots: usize,
+}
+
 #[derive(Debug)]
 struct VirtualMachine {
-    chunk: Chunk,
+    frames: Vec<CallFrame>,
+    max_frames: usize,
     stack: Vec<Value>,
+    heap: Heap,
+    // Keyed by interned names.
+    globals: HashMap<ObjRef, Value>,
+    // Upvalues still pointing to the stack, closed when their slot is popped.
+    open_upvalues: Vec<ObjRef>,
+    // Name of the initializers.
+    init_string: ObjRef,
+    out: Output,
     //ip: usize,
 }
 
 #[derive(Debug)]
 enum InterpretError {
+    // The diagnostics are printed by the compilation.
     Compile,
-    Runtime,
+    Runtime(RuntimeError),
+    ReadFile(String),
     StdinError,
     StdoutError,
     StackUnderflow,
 }
 
+impl InterpretError {
+    /// Exit status of the interpreter, following sysexits.h.
+    fn exit_code(&self) -> i32 {
+        match self {
+            InterpretError::Compile => 65,
+            InterpretError::ReadFile(_)
+            | InterpretError::StdinError
+            | InterpretError::StdoutError => 74,
+            InterpretError::Runtime(_) | InterpretError::StackUnderflow => 70,
+        }
+    }
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile => write!(f, "Compilation failed."),
+            InterpretError::Runtime(err) => write!(f, "{}", err),
+            InterpretError::ReadFile(path) => write!(f, "Could not read file \"{}\".", path),
+            InterpretError::StdinError => write!(f, "Could not read the standard input."),
+            InterpretError::StdoutError => write!(f, "Could not write the standard output."),
+            InterpretError::StackUnderflow => write!(f, "Stack underflow, invalid bytecode."),
+        }
+    }
+}
+
+/// A failure while running the code, where it happened and the calls leading to it.
+#[derive(Debug)]
+struct RuntimeError {
+    message: String,
+    // Line of the failing instruction, reported in the header.
+    line: u32,
+    // `[line 12] in fib()` for each running call, the innermost first.
+    backtrace: Vec<String>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Runtime error: {}", self.line, self.message)?;
+        for frame in &self.backtrace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
 impl VirtualMachine {
-    fn new(chunk: Chunk) -> VirtualMachine {
+    fn new() -> VirtualMachine {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut globals = HashMap::new();
+        for native in native::NATIVES {
+            let name = heap.intern(native.name);
+            let native = heap.alloc(Obj::Native(native));
+            globals.insert(name, Value::Obj(native));
+        }
         VirtualMachine {
-            chunk,
+            frames: Vec::with_capacity(FRAMES_MAX),
+            max_frames: FRAMES_MAX,
             stack: Vec::with_capacity(256),
+            heap,
+            globals,
+            open_upvalues: Vec::new(),
+            init_string,
+            out: Output(Box::new(std::io::stdout())),
+        }
+    }
+
+    /// Deeper calls are a stack overflow.
+    fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames.max(1);
+    }
+
+    /// Mark the roots and free everything else.
+    fn collect_garbage(&mut self) {
+        for value in self.stack.iter() {
+            self.heap.mark_value(*value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(*upvalue);
+        }
+        self.heap.mark_object(self.init_string);
+        for (name, value) in self.globals.iter() {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.collect();
+    }
+
+    /// Every allocation of the virtual machine goes through here, so the
+    /// collector only runs while the roots are known.
+    fn intern(&mut self, s: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(s)
+    }
+
+    /// The references of `obj` must be rooted.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    /// Share the upvalue already pointing to the stack `slot`, if any.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let open = self.open_upvalues.iter().find(|&&upvalue| {
+            matches!(self.heap.get(upvalue), Obj::Upvalue(Upvalue::Open(s)) if *s == slot)
+        });
+        if let Some(&upvalue) = open {
+            return upvalue;
+        }
+        let upvalue = self.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Move the values of the stack slots from `first` to the upvalues capturing them.
+    fn close_upvalues(&mut self, first: usize) {
+        let (heap, stack) = (&mut self.heap, &self.stack);
+        self.open_upvalues
+            .retain(|&upvalue| match heap.get_mut(upvalue) {
+                Obj::Upvalue(upvalue) => match *upvalue {
+                    Upvalue::Open(slot) if slot >= first => {
+                        *upvalue = Upvalue::Closed(stack[slot]);
+                        false
+                    }
+                    Upvalue::Open(_) => true,
+                    Upvalue::Closed(_) => false,
+                },
+                _ => false,
+            });
+    }
+
+    /// The upvalue `idx` of the running closure.
+    fn upvalue(&self, idx: u8) -> ObjRef {
+        let frame = self.frames.last().expect("No function is running.");
+        match self.heap.as_closure(frame.closure) {
+            Some(closure) => closure.upvalues[idx as usize],
+            None => unreachable!("Frames run closures."),
+        }
+    }
+
+    /// Chunk of the function being executed.
+    fn chunk(&self) -> &Chunk {
+        let frame = self.frames.last().expect("No function is running.");
+        match self.heap.as_function(frame.function) {
+            Some(function) => &function.chunk,
+            None => unreachable!("Only functions are called."),
         }
     }
 
+    /// A runtime error at the instruction `ip` of the last frame.
+    fn runtime_error(&self, ip: usize, msg: &str) -> InterpretError {
+        let last = self.frames.len().saturating_sub(1);
+        let backtrace = self
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                // Callers have stored the instruction following their call.
+                let ip = if i == last { ip } else { frame.ip - 1 };
+                let Some(function) = self.heap.as_function(frame.function) else {
+                    unreachable!("Only functions are called.")
+                };
+                let line = get_line(&function.chunk.lines, ip).unwrap_or_default();
+                match &function.name {
+                    Some(name) => format!("[line {}] in {}()", line, name),
+                    None => format!("[line {}] in script", line),
+                }
+            })
+            .collect();
+        InterpretError::Runtime(RuntimeError {
+            message: msg.to_string(),
+            line: get_line(&self.chunk().lines, ip).unwrap_or_default(),
+            backtrace,
+        })
+    }
+
+    /// Drop the calls interrupted by an error, the globals are kept.
+    fn reset_stack(&mut self) {
+        // Closures kept in globals must not point to the cleared stack.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    /// Constants used as names are always interned strings.
+    fn read_name(&self, idx: Index) -> ObjRef {
+        match self.chunk().values[idx.get()] {
+            Value::Obj(name) => name,
+            _ => unreachable!("Names are strings."),
+        }
+    }
+
+    fn undefined_property(&mut self, ip: usize, name: ObjRef) -> InterpretError {
+        let msg = format!(
+            "Undefined property '{}'.",
+            self.heap.as_str(name).unwrap_or_default()
+        );
+        self.runtime_error(ip, &msg)
+    }
+
+    fn undefined_variable(&mut self, ip: usize, name: ObjRef) -> InterpretError {
+        let msg = format!(
+            "Undefined variable '{}'.",
+            self.heap.as_str(name).unwrap_or_default()
+        );
+        self.runtime_error(ip, &msg)
+    }
+
     #[inline]
-    fn exec_binop(stack: &mut Vec<Value>, op: &Opcode) -> Result<(), InterpretError> {
-        let a = stack.pop().ok_or(InterpretError::StackUnderflow)?;
-        let b = stack.last_mut().ok_or(InterpretError::StackUnderflow)?;
-        match op {
-            Opcode::Add => *b += a,
-            Opcode::Sub => *b -= a,
-            Opcode::Div => *b /= a,
-            Opcode::Mul => *b *= a,
-            _ => unreachable!(),
+    fn exec_binop(&mut self, op: Opcode, ip: usize) -> Result<(), InterpretError> {
+        let len = self.stack.len();
+        if len < 2 {
+            return Err(InterpretError::StackUnderflow);
+        }
+        let ret = match (self.stack[len - 2], self.stack[len - 1]) {
+            (Value::Number(b), Value::Number(a)) => match op {
+                Opcode::Add => Value::Number(b + a),
+                Opcode::Sub => Value::Number(b - a),
+                Opcode::Div => Value::Number(b / a),
+                Opcode::Mul => Value::Number(b * a),
+                Opcode::Greater => Value::Bool(b > a),
+                Opcode::Less => Value::Bool(b < a),
+                _ => unreachable!(),
+            },
+            (Value::Obj(b), Value::Obj(a)) if op == Opcode::Add => {
+                match (self.heap.as_str(b), self.heap.as_str(a)) {
+                    (Some(b), Some(a)) => {
+                        let s = [b, a].concat();
+                        Value::Obj(self.intern(&s))
+                    }
+                    _ => return Err(self.runtime_error(ip, ADD_OPERANDS)),
+                }
+            }
+            _ if op == Opcode::Add => return Err(self.runtime_error(ip, ADD_OPERANDS)),
+            _ => return Err(self.runtime_error(ip, "Operands must be numbers.")),
+        };
+        self.stack.pop();
+        self.stack[len - 2] = ret;
+        Ok(())
+    }
+
+    /// Push a frame for the callee below the `count` arguments on the stack.
+    fn call_value(&mut self, count: u8, ip: usize) -> Result<(), InterpretError> {
+        let slots = self
+            .stack
+            .len()
+            .checked_sub(count as usize + 1)
+            .ok_or(InterpretError::StackUnderflow)?;
+        let callee = match self.stack[slots] {
+            Value::Obj(callee) => callee,
+            _ => return Err(self.runtime_error(ip, CALLABLES)),
+        };
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(callee, count, ip),
+            &Obj::Native(native) => {
+                if native.arity != count {
+                    let msg = format!("Expected {} arguments but got {}.", native.arity, count);
+                    return Err(self.runtime_error(ip, &msg));
+                }
+                match (native.function)(&mut self.heap, &self.stack[slots + 1..]) {
+                    Ok(result) => {
+                        self.stack.truncate(slots);
+                        self.stack.push(result);
+                        Ok(())
+                    }
+                    Err(msg) => Err(self.runtime_error(ip, msg)),
+                }
+            }
+            &Obj::BoundMethod(BoundMethod { receiver, method }) => {
+                self.stack[slots] = receiver;
+                self.call(method, count, ip)
+            }
+            Obj::Class(class) => {
+                let initializer = class.methods.get(&self.init_string).copied();
+                let instance = self.alloc(Obj::Instance(Instance::new(callee)));
+                self.stack[slots] = Value::Obj(instance);
+                match initializer {
+                    Some(initializer) => self.call(initializer, count, ip),
+                    None if count != 0 => {
+                        let msg = format!("Expected 0 arguments but got {}.", count);
+                        Err(self.runtime_error(ip, &msg))
+                    }
+                    None => Ok(()),
+                }
+            }
+            _ => Err(self.runtime_error(ip, CALLABLES)),
         }
+    }
+
+    /// Push a frame for the `closure` below the `count` arguments on the stack.
+    fn call(&mut self, closure: ObjRef, count: u8, ip: usize) -> Result<(), InterpretError> {
+        let function = match self.heap.as_closure(closure) {
+            Some(closure) => closure.function,
+            None => unreachable!("Only closures are called."),
+        };
+        let arity = self.heap.as_function(function).map_or(0, |f| f.arity);
+        if arity != count {
+            let msg = format!("Expected {} arguments but got {}.", arity, count);
+            return Err(self.runtime_error(ip, &msg));
+        }
+        if self.frames.len() == self.max_frames {
+            return Err(self.runtime_error(ip, "Stack overflow."));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - count as usize - 1,
+        });
         Ok(())
     }
 
-    fn run(mut self) -> Result<(), InterpretError> {
-        println!("{}", self.chunk.dissemble("debug"));
-        let mut ip = 0;
+    /// Check that `index` is in the bounds of `target`, None if it is not an array.
+    fn array_index(
+        &mut self,
+        target: Value,
+        index: Value,
+        ip: usize,
+    ) -> Result<Option<(ObjRef, usize)>, InterpretError> {
+        let Value::Obj(array) = target else {
+            return Ok(None);
+        };
+        let len = match self.heap.get(array) {
+            Obj::Array(values) => values.len(),
+            _ => return Ok(None),
+        };
+        match index {
+            Value::Number(n) if n.fract() == 0. && n >= 0. && (n as usize) < len => {
+                Ok(Some((array, n as usize)))
+            }
+            Value::Number(n) if n.fract() == 0. => {
+                let msg = format!("Index {} out of bounds for length {}.", n, len);
+                Err(self.runtime_error(ip, &msg))
+            }
+            _ => Err(self.runtime_error(ip, "Array index must be an integer.")),
+        }
+    }
+
+    /// The key of `target` for `index`, None if it is not a map.
+    fn map_key(
+        &mut self,
+        target: Value,
+        index: Value,
+        ip: usize,
+    ) -> Result<Option<(ObjRef, MapKey)>, InterpretError> {
+        match target {
+            Value::Obj(map) if matches!(self.heap.get(map), Obj::Map(_)) => {
+                match self.heap.map_key(index) {
+                    Some(key) => Ok(Some((map, key))),
+                    None => Err(self.runtime_error(ip, native::MAP_KEYS)),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The superclass pushed by the compiler for `super` accesses.
+    fn pop_class(&mut self) -> Result<ObjRef, InterpretError> {
+        match self.stack.pop() {
+            Some(Value::Obj(class)) => Ok(class),
+            Some(_) => unreachable!("`super` is a struct."),
+            None => Err(InterpretError::StackUnderflow),
+        }
+    }
+
+    /// Call the method `name` of the instance below the `count` arguments.
+    fn invoke(&mut self, name: ObjRef, count: u8, ip: usize) -> Result<(), InterpretError> {
+        let slots = self
+            .stack
+            .len()
+            .checked_sub(count as usize + 1)
+            .ok_or(InterpretError::StackUnderflow)?;
+        let instance = match self.stack[slots] {
+            Value::Obj(r) => self.heap.as_instance(r),
+            _ => None,
+        };
+        let Some(instance) = instance else {
+            return Err(self.runtime_error(ip, "Only instances have methods."));
+        };
+        let (class, field) = (instance.class, instance.fields.get(&name).copied());
+        // A field holding a function shadows the method.
+        if let Some(field) = field {
+            self.stack[slots] = field;
+            return self.call_value(count, ip);
+        }
+        self.invoke_from_class(class, name, count, ip)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        count: u8,
+        ip: usize,
+    ) -> Result<(), InterpretError> {
+        let method = self
+            .heap
+            .as_class(class)
+            .and_then(|class| class.methods.get(&name).copied());
+        match method {
+            Some(method) => self.call(method, count, ip),
+            None => Err(self.undefined_property(ip, name)),
+        }
+    }
+
+    /// Replace the instance on top of the stack by its method `name` of `class`.
+    fn bind_method(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        ip: usize,
+    ) -> Result<(), InterpretError> {
+        let method = self
+            .heap
+            .as_class(class)
+            .and_then(|class| class.methods.get(&name).copied());
+        let Some(method) = method else {
+            return Err(self.undefined_property(ip, name));
+        };
+        let receiver = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+        let bound = self.alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        if let Some(top) = self.stack.last_mut() {
+            *top = Value::Obj(bound);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), InterpretError> {
+        // The instruction pointer and slots of the last frame are kept at hand.
+        let frame = self.frames.last().expect("No function to run.");
+        let (mut ip, mut slots) = (frame.ip, frame.slots);
         loop {
-            let opcode = &self.chunk.code.get(ip).ok_or(InterpretError::Runtime)?;
+            // Every function ends with a return, running past it is a compiler bug.
+            let opcode = *self
+                .chunk()
+                .code
+                .get(ip)
+                .ok_or_else(|| self.runtime_error(ip, "Instruction out of the code."))?;
+            #[cfg(feature = "debug_trace_execution")]
+            println!("{:?}\n{:04} {}", self.stack, ip, opcode);
             match opcode {
-                Opcode::Add | Opcode::Mul | Opcode::Div | Opcode::Sub => {
-                    Self::exec_binop(&mut self.stack, opcode)?
+                Opcode::Add
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Sub
+                | Opcode::Greater
+                | Opcode::Less => self.exec_binop(opcode, ip)?,
+                Opcode::Equal => {
+                    let a = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let b = self
+                        .stack
+                        .last_mut()
+                        .ok_or(InterpretError::StackUnderflow)?;
+                    *b = Value::Bool(*b == a);
+                }
+                Opcode::Not => {
+                    let a = self
+                        .stack
+                        .last_mut()
+                        .ok_or(InterpretError::StackUnderflow)?;
+                    *a = Value::Bool(a.is_falsey());
                 }
+                Opcode::Nil => self.stack.push(Value::Nil),
+                Opcode::True => self.stack.push(Value::Bool(true)),
+                Opcode::False => self.stack.push(Value::Bool(false)),
                 Opcode::Negate => {
                     let a = self
                         .stack
                         .last_mut()
                         .ok_or(InterpretError::StackUnderflow)?;
-                    *a = -*a;
+                    match a {
+                        Value::Number(n) => *n = -*n,
+                        _ => return Err(self.runtime_error(ip, "Operand must be a number.")),
+                    }
                 }
                 Opcode::Return => {
-                    let ret = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
-                    println!("{}", ret);
-                    return Ok(());
+                    let result = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    self.frames.pop();
+                    self.close_upvalues(slots);
+                    self.stack.truncate(slots);
+                    let Some(caller) = self.frames.last() else {
+                        return Ok(());
+                    };
+                    self.stack.push(result);
+                    (ip, slots) = (caller.ip, caller.slots);
+                    continue;
+                }
+                Opcode::Call(count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = ip + 1;
+                    }
+                    self.call_value(count, ip)?;
+                    // Not a new frame when constructing an instance without initializer.
+                    let frame = self.frames.last().expect("The caller is still running.");
+                    (ip, slots) = (frame.ip, frame.slots);
+                    continue;
+                }
+                Opcode::Invoke(name, count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = ip + 1;
+                    }
+                    let name = self.read_name(name.into());
+                    self.invoke(name, count, ip)?;
+                    let frame = self.frames.last().expect("The caller is still running.");
+                    (ip, slots) = (frame.ip, frame.slots);
+                    continue;
+                }
+                Opcode::SuperInvoke(name, count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = ip + 1;
+                    }
+                    let name = self.read_name(name.into());
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, name, count, ip)?;
+                    let frame = self.frames.last().expect("The caller is still running.");
+                    (ip, slots) = (frame.ip, frame.slots);
+                    continue;
+                }
+                Opcode::GetSuper(n) => {
+                    let name = self.read_name(n);
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name, ip)?;
+                }
+                Opcode::Inherit => {
+                    let len = self.stack.len();
+                    if len < 2 {
+                        return Err(InterpretError::StackUnderflow);
+                    }
+                    let methods = match self.stack[len - 2] {
+                        Value::Obj(r) => self.heap.as_class(r).map(|class| class.methods.clone()),
+                        _ => None,
+                    };
+                    let Some(methods) = methods else {
+                        return Err(self.runtime_error(ip, "Superclass must be a struct."));
+                    };
+                    let Value::Obj(subclass) = self.stack[len - 1] else {
+                        unreachable!("Only structs inherit.");
+                    };
+                    if let Obj::Class(subclass) = self.heap.get_mut(subclass) {
+                        subclass.methods.extend(methods);
+                    }
+                    self.stack.pop();
+                }
+                Opcode::Array(count) => {
+                    let first = self
+                        .stack
+                        .len()
+                        .checked_sub(count as usize)
+                        .ok_or(InterpretError::StackUnderflow)?;
+                    // The elements stay rooted on the stack until the array is allocated.
+                    let values = self.stack[first..].to_vec();
+                    let array = self.alloc(Obj::Array(values));
+                    self.stack.truncate(first);
+                    self.stack.push(Value::Obj(array));
+                }
+                Opcode::Map(count) => {
+                    let first = self
+                        .stack
+                        .len()
+                        .checked_sub(2 * count as usize)
+                        .ok_or(InterpretError::StackUnderflow)?;
+                    let mut map = Map::default();
+                    for i in (first..self.stack.len()).step_by(2) {
+                        let Some(key) = self.heap.map_key(self.stack[i]) else {
+                            return Err(self.runtime_error(ip, native::MAP_KEYS));
+                        };
+                        map.insert(key, self.stack[i + 1]);
+                    }
+                    // The entries stay rooted on the stack until the map is allocated.
+                    let map = self.alloc(Obj::Map(map));
+                    self.stack.truncate(first);
+                    self.stack.push(Value::Obj(map));
+                }
+                Opcode::GetIndex => {
+                    let index = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let target = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    let value = if let Some((map, key)) = self.map_key(target, index, ip)? {
+                        match self.heap.get(map) {
+                            Obj::Map(map) => map.get(key).unwrap_or(Value::Nil),
+                            _ => unreachable!("Checked by map_key."),
+                        }
+                    } else {
+                        let Some((array, idx)) = self.array_index(target, index, ip)? else {
+                            return Err(self.runtime_error(ip, INDEXABLES));
+                        };
+                        match self.heap.get(array) {
+                            Obj::Array(values) => values[idx],
+                            _ => unreachable!("Checked by array_index."),
+                        }
+                    };
+                    if let Some(top) = self.stack.last_mut() {
+                        *top = value;
+                    }
+                }
+                Opcode::SetIndex => {
+                    let value = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let index = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let target = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    if let Some((map, key)) = self.map_key(target, index, ip)? {
+                        if let Obj::Map(map) = self.heap.get_mut(map) {
+                            map.insert(key, value);
+                        }
+                    } else {
+                        let Some((array, idx)) = self.array_index(target, index, ip)? else {
+                            return Err(self.runtime_error(ip, INDEXABLES));
+                        };
+                        if let Obj::Array(values) = self.heap.get_mut(array) {
+                            values[idx] = value;
+                        }
+                    }
+                    if let Some(top) = self.stack.last_mut() {
+                        *top = value;
+                    }
+                }
+                Opcode::Class(n) => {
+                    let name = self.read_name(n);
+                    let name = self.heap.as_str(name).unwrap_or_default().into();
+                    let class = self.alloc(Obj::Class(Class::new(name)));
+                    self.stack.push(Value::Obj(class));
+                }
+                Opcode::Method(n) => {
+                    let name = self.read_name(n);
+                    let method = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let (Value::Obj(method), Some(&Value::Obj(class))) =
+                        (method, self.stack.last())
+                    else {
+                        unreachable!("Methods are closures attached to a struct.");
+                    };
+                    if let Obj::Class(class) = self.heap.get_mut(class) {
+                        class.methods.insert(name, method);
+                    }
+                }
+                Opcode::GetProperty(n) => {
+                    let name = self.read_name(n);
+                    let instance = match self.stack.last() {
+                        Some(Value::Obj(r)) => self.heap.as_instance(*r),
+                        _ => None,
+                    };
+                    let Some(instance) = instance else {
+                        return Err(self.runtime_error(ip, "Only instances have properties."));
+                    };
+                    match instance.fields.get(&name).copied() {
+                        Some(value) => {
+                            if let Some(top) = self.stack.last_mut() {
+                                *top = value;
+                            }
+                        }
+                        None => self.bind_method(instance.class, name, ip)?,
+                    }
+                }
+                Opcode::SetProperty(n) => {
+                    let name = self.read_name(n);
+                    let value = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    let instance = match self.stack.last() {
+                        Some(&Value::Obj(r)) => match self.heap.get_mut(r) {
+                            Obj::Instance(instance) => Some(instance),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let Some(instance) = instance else {
+                        return Err(self.runtime_error(ip, "Only instances have fields."));
+                    };
+                    instance.fields.insert(name, value);
+                    if let Some(top) = self.stack.last_mut() {
+                        *top = value;
+                    }
+                }
+                Opcode::Print => {
+                    let value = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    writeln!(self.out.0, "{}", value.display(&self.heap))
+                        .map_err(|_| InterpretError::StdoutError)?;
+                }
+                Opcode::Pop => {
+                    self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
                 }
                 Opcode::Constant(n) => {
-                    let constant = self.chunk.values[*n as usize];
+                    let constant = self.chunk().values[n as usize];
+                    self.stack.push(constant);
+                }
+                Opcode::ConstantLong(n) => {
+                    let constant = self.chunk().values[n.get()];
                     self.stack.push(constant);
-                    println!("{}", constant)
                 }
                 Opcode::Litteral(litteral) => {
-                    self.stack.push(*litteral as Value);
-                    println!("{}", litteral)
+                    self.stack.push(Value::Number(litteral as f64));
+                }
+                Opcode::GetLocal(slot) => self.stack.push(self.stack[slots + slot as usize]),
+                Opcode::SetLocal(slot) => {
+                    let value = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    self.stack[slots + slot as usize] = value;
+                }
+                Opcode::GetUpvalue(idx) => {
+                    let value = match self.heap.get(self.upvalue(idx)) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => unreachable!("Closures capture upvalues."),
+                    };
+                    self.stack.push(value);
+                }
+                Opcode::SetUpvalue(idx) => {
+                    let value = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    match self.heap.get_mut(self.upvalue(idx)) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot] = value,
+                        Obj::Upvalue(Upvalue::Closed(closed)) => *closed = value,
+                        _ => unreachable!("Closures capture upvalues."),
+                    }
+                }
+                Opcode::CloseUpvalue => {
+                    let top = self
+                        .stack
+                        .len()
+                        .checked_sub(1)
+                        .ok_or(InterpretError::StackUnderflow)?;
+                    self.close_upvalues(top);
+                    self.stack.pop();
+                }
+                Opcode::Closure(n) => {
+                    let Value::Obj(function) = self.chunk().values[n.get()] else {
+                        unreachable!("Closures wrap functions.");
+                    };
+                    let captures = match self.heap.as_function(function) {
+                        Some(function) => function.upvalues.clone(),
+                        None => unreachable!("Closures wrap functions."),
+                    };
+                    let mut upvalues = Vec::with_capacity(captures.len());
+                    for capture in captures {
+                        let upvalue = if capture.local {
+                            self.capture_upvalue(slots + capture.index as usize)
+                        } else {
+                            self.upvalue(capture.index)
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                Opcode::Jump(offset) => {
+                    ip += offset as usize;
+                    continue;
+                }
+                Opcode::JumpIfFalse(offset) => {
+                    let cond = self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    if cond.is_falsey() {
+                        ip += offset as usize;
+                        continue;
+                    }
+                }
+                Opcode::Loop(offset) => {
+                    ip -= offset as usize;
+                    continue;
+                }
+                Opcode::DefineGlobal(n) => {
+                    let name = self.read_name(n);
+                    let value = self.stack.pop().ok_or(InterpretError::StackUnderflow)?;
+                    self.globals.insert(name, value);
+                }
+                Opcode::GetGlobal(n) => {
+                    let name = self.read_name(n);
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(self.undefined_variable(ip, name)),
+                    }
+                }
+                Opcode::SetGlobal(n) => {
+                    let name = self.read_name(n);
+                    let value = *self.stack.last().ok_or(InterpretError::StackUnderflow)?;
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(ip, name)),
+                    }
                 }
             }
             ip += 1;
-            println!("{:?}", self.stack);
         }
     }
 
-    fn compile(&mut self, code: &str) -> Result<Chunk, InterpretError> {
-        let mut code = lexer::Span::new(code.as_bytes());
-        loop {
-            // TODO manage error real world will crash at the end of file ahah.
-            match lexer::scan_token(code) {
-                Ok((next_code, token)) => {
-                    code = next_code;
-                    println!(
-                        "Token: {token:#?} {} {}",
-                        next_code.location_line(),
-                        next_code.location_offset()
-                    );
-                    if let lexer::Token::EOF = token {
-                        return Err(InterpretError::Compile);
-                    }
-                }
-                //Err(ScanError::End) => unimplemented!("End of file!"),
-                Err(_) => return Err(InterpretError::Compile),
-            }
+    /// Compile, printing the diagnostics quoting `file` if any.
+    fn compile(&mut self, code: &str, file: Option<&str>) -> Result<Function, InterpretError> {
+        let mut compiler = compiler::Compiler::new(code, &mut self.heap);
+        if let Some(file) = file {
+            compiler.set_file(file);
         }
+        compiler.compile().map_err(|errors| {
+            let color = std::io::stdout().is_terminal();
+            for error in errors {
+                eprintln!("{}", error.display(color));
+            }
+            InterpretError::Compile
+        })
     }
 
     fn run_file<P: AsRef<Path>>(&mut self, source_code: P) -> Result<(), InterpretError> {
-        let code = std::fs::read_to_string(source_code).expect("Cannot found file?!");
-        let _chunk = self.compile(&code);
-        unimplemented!()
+        let path = source_code.as_ref();
+        let code = std::fs::read_to_string(path)
+            .map_err(|_| InterpretError::ReadFile(path.display().to_string()))?;
+        let script = self.compile(&code, Some(&path.to_string_lossy()))?;
+        self.run_script(script)
     }
 
     fn eval(&mut self, code: &str) -> Result<(), InterpretError> {
-        let _chunck = self.compile(code)?;
-        Ok(())
+        let script = self.compile(code, None)?;
+        self.run_script(script)
+    }
+
+    fn run_script(&mut self, script: Function) -> Result<(), InterpretError> {
+        // Not a safe point, the constants of the script are not rooted yet.
+        let function = self.heap.alloc(Obj::Function(script));
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::Obj(closure));
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - 1,
+        });
+        let result = self.run();
+        if result.is_err() {
+            self.reset_stack();
+        }
+        result
     }
 
     fn repl(mut self) -> Result<(), InterpretError> {
@@ -224,33 +1190,734 @@ impl VirtualMachine {
         let mut stdout = std::io::stdout();
         let stdin = std::io::stdin();
         loop {
+            buffer.clear();
             print!(">>> ");
             stdout.flush().map_err(|_| InterpretError::StdoutError)?;
-            stdin
+            let read = stdin
                 .read_line(&mut buffer)
                 .map_err(|_| InterpretError::StdinError)?;
+            if read == 0 {
+                return Ok(());
+            }
             println!();
-            self.eval(&buffer)?;
+            // Keep the session going after an error in the line.
+            match self.eval(&buffer) {
+                Ok(()) | Err(InterpretError::Compile) => {}
+                Err(err @ (InterpretError::Runtime(_) | InterpretError::StackUnderflow)) => {
+                    eprintln!("{}", err)
+                }
+                Err(err) => return Err(err),
+            }
         }
     }
 }
 
+const USAGE: &str =
+    "Usage: rlox [--gc-stress] [--gc-growth=<factor>] [--max-frames=<depth>] [path]";
+
+/// Exit with EX_USAGE from sysexits.h.
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(64)
+}
+
 fn main() {
-    let mut code = Chunk::new();
-    code.write_value(42.);
-    code.write_opcode(Opcode::Constant(0), 1);
-    code.write_opcode(Opcode::Negate, 2);
-    code.write_opcode(Opcode::Litteral(1152), 2);
-    code.write_opcode(Opcode::Add, 3);
-    code.write_opcode(Opcode::Return, 4);
-    let mut vm = VirtualMachine::new(code);
-    let mut args = args();
-    if (args.len()) == 1 {
-        vm.repl().expect("Whops REPL ERROR");
-    } else if args.len() == 2 {
-        let file = args.next().expect("Missing filepath");
-        vm.run_file(&file).expect("Whops Compile/Interp ERROR");
-    } else {
-        println!("Usage: rlox [path]");
+    let mut vm = VirtualMachine::new();
+    let mut file = None;
+    for arg in args().skip(1) {
+        if arg == "--gc-stress" {
+            vm.heap.set_stress(true);
+        } else if let Some(factor) = arg.strip_prefix("--gc-growth=") {
+            match factor.parse() {
+                Ok(factor) => vm.heap.set_growth_factor(factor),
+                Err(_) => usage(),
+            }
+        } else if let Some(depth) = arg.strip_prefix("--max-frames=") {
+            match depth.parse() {
+                Ok(depth) => vm.set_max_frames(depth),
+                Err(_) => usage(),
+            }
+        } else if file.is_none() {
+            file = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let result = match file {
+        None => vm.repl(),
+        Some(file) => vm.run_file(&file),
+    };
+    if let Err(err) = result {
+        if !matches!(err, InterpretError::Compile) {
+            eprintln!("{}", err);
+        }
+        std::process::exit(err.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod test_vm {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Keep what is printed to check it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `code` in a fresh virtual machine, returning what it printed.
+    fn run(code: &str) -> Result<String, InterpretError> {
+        let mut vm = VirtualMachine::new();
+        let out = SharedBuffer::default();
+        vm.out = Output(Box::new(out.clone()));
+        vm.eval(code)?;
+        let printed = out.0.borrow();
+        Ok(String::from_utf8_lossy(&printed).into_owned())
+    }
+
+    /// Run `setup` collecting at every allocation, collect once more, then
+    /// run `check` and return what it printed.
+    fn run_stressed(setup: &str, check: &str) -> String {
+        let mut vm = VirtualMachine::new();
+        vm.heap.set_stress(true);
+        assert!(vm.eval(setup).is_ok());
+        vm.collect_garbage();
+        let out = SharedBuffer::default();
+        vm.out = Output(Box::new(out.clone()));
+        assert!(vm.eval(check).is_ok());
+        let printed = out.0.borrow();
+        String::from_utf8_lossy(&printed).into_owned()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(run("print (1 + 2) * -3 / 4;").unwrap(), "-2.25\n");
+        assert_eq!(run("print nil;").unwrap(), "nil\n");
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(run("print 1;\n2 + 3;\nprint \"two\";").unwrap(), "1\ntwo\n");
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(run("print !(1 < 2) == false;").unwrap(), "true\n");
+        assert_eq!(run("print nil != false;").unwrap(), "true\n");
+        assert_eq!(run("print 1 >= 2;").unwrap(), "false\n");
+        assert!(matches!(run("1 < nil;"), Err(InterpretError::Runtime(_))));
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(run("print \"a\" + \"b\";").unwrap(), "ab\n");
+        assert_eq!(
+            run("print \"con\" + \"cat\" == \"concat\";").unwrap(),
+            "true\n"
+        );
+        assert!(matches!(run("\"a\" + 1;"), Err(InterpretError::Runtime(_))));
+        assert!(matches!(
+            run("\"a\" - \"b\";"),
+            Err(InterpretError::Runtime(_))
+        ));
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let mut vm = VirtualMachine::new();
+        vm.heap.set_stress(true);
+        assert!(vm
+            .eval("(\"a\" + \"b\") + (\"c\" + \"d\") == \"ab\" + \"cd\";")
+            .is_ok());
+        // Once the script returned, only the name of initializers and the
+        // natives are reachable.
+        vm.collect_garbage();
+        assert!(format!("{:?}", vm.heap).starts_with("Heap { objects: 13,"));
+    }
+
+    #[test]
+    fn test_globals() {
+        assert_eq!(
+            run("let a = 1;\nlet b;\nprint b;\nb = a = a + 1;\nprint a + b;").unwrap(),
+            "nil\n4\n"
+        );
+        assert_eq!(
+            run("let s = \"a\";\nlet s = s + \"b\";\nprint s;").unwrap(),
+            "ab\n"
+        );
+        assert!(matches!(run("print x;"), Err(InterpretError::Runtime(_))));
+        assert!(matches!(run("x = 1;"), Err(InterpretError::Runtime(_))));
+    }
+
+    #[test]
+    fn test_locals() {
+        let code = "let a = \"global\";
+{
+    let a = \"outer\";
+    {
+        let b = a + \" inner\";
+        a = b;
+        print b;
+    }
+    print a;
+}
+print a;";
+        assert_eq!(run(code).unwrap(), "outer inner\nouter inner\nglobal\n");
+    }
+
+    #[test]
+    fn test_control_flow() {
+        assert_eq!(
+            run("if (1 < 2) print \"then\"; else print \"else\";").unwrap(),
+            "then\n"
+        );
+        assert_eq!(
+            run("if (nil) print \"then\"; else print \"else\";").unwrap(),
+            "else\n"
+        );
+        assert_eq!(run("if (false) print 1; print 2;").unwrap(), "2\n");
+        let code = "let i = 0;
+while (i < 3) {
+    let j = i * 2;
+    print j;
+    i = i + 1;
+}
+print i;";
+        assert_eq!(run(code).unwrap(), "0\n2\n4\n3\n");
+    }
+
+    #[test]
+    fn test_logical() {
+        let code = "print nil or \"default\";
+print 1 or 2;
+print false and 1;
+print 1 and 2;
+print 1 == 2 or 2 < 3 and !nil;";
+        assert_eq!(run(code).unwrap(), "default\n1\nfalse\n2\ntrue\n");
+        // The right operand is not evaluated.
+        assert_eq!(
+            run("let a = 0; true or (a = 1); false and (a = 2); print a;").unwrap(),
+            "0\n"
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let code = "for (let i = 0; i < 10; i = i + 1) {
+    if (i == 1) continue;
+    let j = i * 10;
+    if (i == 4) break;
+    print j;
+}";
+        assert_eq!(run(code).unwrap(), "0\n20\n30\n");
+        let code = "let n = 0;
+loop {
+    let step = 1;
+    n = n + step;
+    if (n < 3) { let skip = true; continue; }
+    while (true) { let inner = n; break; }
+    break;
+}
+print n;";
+        assert_eq!(run(code).unwrap(), "3\n");
+        let code = "let i = 0;
+for (; i < 3;) i = i + 1;
+print i;";
+        assert_eq!(run(code).unwrap(), "3\n");
+    }
+
+    #[test]
+    fn test_functions() {
+        let code = "fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 2) + fib(n - 1);
+}
+print fib(10);
+print fib;
+fun nothing() {}
+print nothing();
+{
+    fun local(a) { let b = a * 2; return b + 1; }
+    print local(local(1));
+}";
+        assert_eq!(run(code).unwrap(), "55\n<fn fib>\nnil\n7\n");
+    }
+
+    #[test]
+    fn test_closures() {
+        let code = "fun counter() {
+    let count = 0;
+    fun increment() { count = count + 1; return count; }
+    return increment;
+}
+let a = counter();
+let b = counter();
+print a();
+print a();
+print b();
+fun adder(x) { fun add(y) { return x + y; } return add; }
+let add2 = adder(2);
+print add2(40);
+print add2;";
+        assert_eq!(run(code).unwrap(), "1\n2\n1\n42\n<fn add>\n");
+    }
+
+    #[test]
+    fn test_closures_share_variables() {
+        let code = "let get;
+let set;
+{
+    let shared = \"before\";
+    fun g() { return shared; }
+    fun s(v) { shared = v; }
+    get = g;
+    set = s;
+    s(\"open\");
+    print shared;
+}
+print get();
+set(\"closed\");
+print get();";
+        assert_eq!(run(code).unwrap(), "open\nopen\nclosed\n");
+        // Each iteration has its own variable.
+        let code = "let first;
+for (let i = 0; i < 3; i = i + 1) {
+    let j = i;
+    fun f() { return j; }
+    if (j == 0) first = f;
+    if (j == 2) break;
+}
+print first();";
+        assert_eq!(run(code).unwrap(), "0\n");
+    }
+
+    #[test]
+    fn test_closures_gc_stress() {
+        let code = "fun make(prefix) {
+    let s = prefix + \"!\";
+    fun f(x) { return s + x; }
+    return f;
+}
+let f = make(\"a\" + \"b\");
+f(\"c\");";
+        assert_eq!(run_stressed(code, "print f(\"d\");"), "ab!d\n");
+    }
+
+    #[test]
+    fn test_structs() {
+        let code = "struct Point {
+    init(x, y) {
+        self.x = x;
+        self.y = y;
+    }
+    sum() { return self.x + self.y; }
+    scale(k) {
+        fun apply(v) { return v * k; }
+        return Point(apply(self.x), apply(self.y));
+    }
+}
+let p = Point(1, 2);
+print p.sum();
+let q = p.scale(10);
+print q.x;
+let sum = q.sum;
+print sum();
+print sum;
+print Point;
+print p;
+q.y = 0;
+print q.sum();
+struct Empty {}
+let e = Empty();
+fun fun_field() { return \"field\"; }
+e.f = fun_field;
+print e.f();
+print p.init(5, 5).sum();";
+        assert_eq!(
+            run(code).unwrap(),
+            "3\n10\n30\n<fn sum>\nPoint\nPoint instance\n10\nfield\n10\n"
+        );
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let code = "struct Shape {
+    init(name) { self.name = name; }
+    describe() { return self.name + \" of area \" + self.area(); }
+    area() { return \"unknown\"; }
+}
+struct Square < Shape {
+    init(side) {
+        super.init(\"square\");
+        self.side = side;
+    }
+    area() { return \"side squared\"; }
+}
+struct Unit < Square {
+    init() { super.init(1); }
+    describe() {
+        let parent = super.describe;
+        return \"unit \" + parent();
+    }
+}
+print Shape(\"blob\").describe();
+print Square(2).describe();
+print Unit().describe();
+print Unit().side;";
+        assert_eq!(
+            run(code).unwrap(),
+            "blob of area unknown\nsquare of area side squared\nunit square of area side squared\n1\n"
+        );
+        // Methods are copied down when the struct is declared.
+        let code = "struct A { m() { return \"a\"; } }
+struct B < A {}
+struct A { m() { return \"changed\"; } }
+print B().m();";
+        assert_eq!(run(code).unwrap(), "a\n");
+        assert!(matches!(
+            run("let A = 1; struct B < A {}"),
+            Err(InterpretError::Runtime(_))
+        ));
+        assert!(matches!(
+            run("struct A {} struct B < A { m() { return super.missing; } } B().m();"),
+            Err(InterpretError::Runtime(_))
+        ));
+    }
+
+    #[test]
+    fn test_structs_errors() {
+        let errors = [
+            "struct A {} A(1);",
+            "struct A { init(a) {} } A();",
+            "struct A {} A().missing;",
+            "struct A {} A().missing();",
+            "let a = 1; a.field;",
+            "let a = 1; a.field = 2;",
+            "let a = 1; a.method();",
+        ];
+        for code in errors {
+            assert!(
+                matches!(run(code), Err(InterpretError::Runtime(_))),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_structs_gc_stress() {
+        let code = "struct Node {
+    init(value, next) { self.value = value; self.next = next; }
+    total() {
+        if (self.next == nil) return self.value;
+        return self.value + self.next.total();
+    }
+}
+let list = Node(\"a\", Node(\"b\" + \"c\", nil));
+let bound = list.next.total;";
+        assert_eq!(
+            run_stressed(code, "print list.total() + bound();"),
+            "abcbc\n"
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        let code = "let a = [1, \"two\", [3]];
+print a;
+print a[1] + \"!\";
+print a[2][0];
+a[0] = a[0] + 10;
+a[2][0] = nil;
+print a;
+print len(a);
+push(a, true);
+print pop(a);
+print len([]);
+let squares = [];
+for (let i = 0; i < 4; i = i + 1) push(squares, i * i);
+print squares;
+print len(\"four\");
+print len;";
+        assert_eq!(
+            run(code).unwrap(),
+            "[1, two, [3]]\ntwo!\n3\n[11, two, [nil]]\n3\ntrue\n0\n[0, 1, 4, 9]\n4\n<native fn len>\n"
+        );
+        assert_eq!(
+            run("let a = []; push(a, a); print a;").unwrap(),
+            "[[...]]\n"
+        );
+    }
+
+    #[test]
+    fn test_arrays_errors() {
+        let errors = [
+            "[1][1];",
+            "[1][-1];",
+            "[1][0.5];",
+            "[1][\"0\"];",
+            "let a = [1]; a[1] = 2;",
+            "1[0];",
+            "pop([]);",
+            "push(1, 2);",
+            "len(1, 2);",
+        ];
+        for code in errors {
+            assert!(
+                matches!(run(code), Err(InterpretError::Runtime(_))),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_arrays_gc_stress() {
+        let code = "let a = [\"a\" + \"b\", [\"c\" + \"d\"]];
+push(a[1], \"e\" + \"f\");";
+        assert_eq!(run_stressed(code, "print a;"), "[ab, [cd, ef]]\n");
+    }
+
+    #[test]
+    fn test_maps() {
+        let code = "let m = {\"one\": 1, 2: \"two\", true: nil};
+print m;
+print m[1 + 1];
+m[\"one\"] = 10;
+m[nil] = {};
+m[nil][3] = [3];
+print m[\"o\" + \"ne\"];
+print m[nil];
+print m[\"missing\"];
+print len(m);
+print keys(m);
+print values({1: 2, 3: 4});
+print has(m, true);
+print has(m, false);
+let sum = 0;
+let ks = keys({1: 0, 2: 0, 0: 0});
+for (let i = 0; i < len(ks); i = i + 1) sum = sum + ks[i];
+print sum;
+print {0: \"zero\"}[-0];
+{ print {}; }";
+        assert_eq!(
+            run(code).unwrap(),
+            "{one: 1, 2: two, true: nil}\ntwo\n10\n{3: [3]}\nnil\n4\n[one, 2, true, nil]\n[2, 4]\ntrue\nfalse\n3\nzero\n{}\n"
+        );
+        assert_eq!(
+            run("let m = {}; m[\"self\"] = m; m[1] = [m]; print m;").unwrap(),
+            "{self: {...}, 1: [{...}]}\n"
+        );
+    }
+
+    #[test]
+    fn test_maps_errors() {
+        let errors = [
+            "let m = {[]: 1};",
+            "let m = {}; m[[]];",
+            "let m = {}; m[{}] = 1;",
+            "nil[\"k\"];",
+            "keys([]);",
+            "has({}, []);",
+        ];
+        for code in errors {
+            assert!(
+                matches!(run(code), Err(InterpretError::Runtime(_))),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_maps_gc_stress() {
+        let code = "let m = {\"a\" + \"b\": [\"c\" + \"d\"]};
+m[\"e\" + \"f\"] = {1: \"g\" + \"h\"};
+let k = keys(m);";
+        assert_eq!(
+            run_stressed(code, "print m; print k;"),
+            "{ab: [cd], ef: {1: gh}}\n[ab, ef]\n"
+        );
+    }
+
+    #[test]
+    fn test_runtime_backtrace() {
+        let mut vm = VirtualMachine::new();
+        let out = SharedBuffer::default();
+        vm.out = Output(Box::new(out.clone()));
+        let code = "fun fib(n) {
+  if (n < 2) return n + nil;
+  return fib(n - 1);
+}
+let f = fib;
+fib(2);";
+        let Err(InterpretError::Runtime(err)) = vm.eval(code) else {
+            panic!("Expected a runtime error.");
+        };
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "[line 2] Runtime error: {}\n[line 2] in fib()\n[line 3] in fib()\n[line 6] in script",
+                ADD_OPERANDS
+            )
+        );
+        // The stack is reset and the globals kept, like in the REPL.
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert!(vm.eval("print f;").is_ok());
+
+        let Err(InterpretError::Runtime(err)) = vm.eval("print 1;\nprint -nil;") else {
+            panic!("Expected a runtime error.");
+        };
+        assert_eq!(err.line, 2);
+        assert_eq!(err.backtrace, ["[line 2] in script"]);
+        // What ran before the errors is still printed.
+        assert_eq!(out.0.borrow().as_slice(), b"<fn fib>\n1\n");
+    }
+
+    #[test]
+    fn test_call_errors() {
+        assert!(matches!(
+            run("fun f(a) {} f();"),
+            Err(InterpretError::Runtime(_))
+        ));
+        assert!(matches!(run("\"f\"();"), Err(InterpretError::Runtime(_))));
+        let mut vm = VirtualMachine::new();
+        vm.set_max_frames(10);
+        let code = "fun depth(n) { if (n == 0) return 0; return depth(n - 1); }";
+        assert!(vm.eval(&format!("{} depth(8);", code)).is_ok());
+        assert!(matches!(
+            vm.eval("depth(9);"),
+            Err(InterpretError::Runtime(_))
+        ));
+        // The virtual machine is usable after an error.
+        assert!(vm.eval("depth(8);").is_ok());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_functions_gc_stress() {
+        let code = "fun twice(s) { let t = s + s; return t; }
+let s = twice(twice(\"a\") + \"b\");";
+        assert_eq!(
+            run_stressed(code, "print s; print twice;"),
+            "aabaab\n<fn twice>\n"
+        );
+    }
+
+    #[test]
+    fn test_globals_survive_gc() {
+        let mut vm = VirtualMachine::new();
+        vm.heap.set_stress(true);
+        assert!(vm.eval("let a = \"a\" + \"b\";").is_ok());
+        assert!(vm.eval("let b = a + \"c\";").is_ok());
+        vm.collect_garbage();
+        let b = vm.heap.intern("b");
+        let value = vm.globals[&b];
+        assert_eq!(value.display(&vm.heap).to_string(), "abc");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut vm = VirtualMachine::new();
+        let chunk = vm.compile("print -\"a\";\n2;", None).unwrap().chunk;
+        assert_eq!(
+            chunk.dissemble("test", &vm.heap),
+            "=== test ===
+0000:ln 1 CONSTANT 0 a
+0001:ln 1 NEGATE
+0002:ln 1 PRINT
+0003:ln 2 LITERRAL 2
+0004:ln 2 POP
+0005:ln 2 NIL
+0006:ln 2 RETURN
+========"
+        );
+    }
+
+    #[test]
+    fn test_constant_long() {
+        assert_eq!(std::mem::size_of::<Opcode>(), 4);
+        // Past the first 256 constants, and reusing the earlier ones.
+        let sum: String = (0..300).map(|i| format!(" + {}.5", i)).collect();
+        let code = format!("let s = 0{};\nprint s;\nprint s - 0.5 - 299.5;", sum);
+        assert_eq!(run(&code).unwrap(), "45000\n44700\n");
+
+        let mut vm = VirtualMachine::new();
+        let code: String = (0..257).map(|i| format!("{}.5;", i)).collect();
+        let chunk = vm.compile(&code, None).unwrap().chunk;
+        let listing = chunk.display(&vm.heap).to_string();
+        assert!(listing.contains("0512:ln 1 CONSTANT_LONG 256 256.5\n"));
+    }
+
+    #[test]
+    fn test_many_names() {
+        // Globals, functions, structs and methods past the first 256 constants.
+        let mut code: String = (0..300)
+            .map(|i| format!("let v{} = {}.5;\n", i, i))
+            .collect();
+        code += &(0..300)
+            .map(|i| format!("fun f{}() {{ return {}; }}\n", i, i))
+            .collect::<String>();
+        // The method name comes after 300 numbers in the chunk of B.m.
+        let sum: String = (0..300).map(|i| format!(" + {}.5", i)).collect();
+        code += "struct A { m(a) { return a + 1; } }\n";
+        code += &format!(
+            "struct B < A {{ m(a) {{ 0{}; return super.m(a) * 2; }} n() {{ return super.m; }} }}\n",
+            sum
+        );
+        code += "let b = B();
+b.p = v299;
+print v0 + v299 + v128;
+print f0() + f299() + f128();
+print b.m(1);
+print b.n()(b.p);
+v299 = 1;
+print v299;";
+        assert_eq!(run(&code).unwrap(), "428.5\n427\n4\n300.5\n1\n");
+    }
+
+    #[test]
+    fn test_disassemble_jumps() {
+        let mut vm = VirtualMachine::new();
+        let chunk = vm.compile("while (true) if (nil) 1;", None).unwrap().chunk;
+        assert_eq!(
+            chunk.display(&vm.heap).to_string(),
+            "0000:ln 1 TRUE
+0001:ln 1 JUMP_IF_FALSE 10 -> 0011
+0002:ln 1 POP
+0003:ln 1 NIL
+0004:ln 1 JUMP_IF_FALSE 5 -> 0009
+0005:ln 1 POP
+0006:ln 1 LITERRAL 1
+0007:ln 1 POP
+0008:ln 1 JUMP 2 -> 0010
+0009:ln 1 POP
+0010:ln 1 LOOP 10 -> 0000
+0011:ln 1 POP
+0012:ln 1 NIL
+0013:ln 1 RETURN
+"
+        );
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let mut vm = VirtualMachine::new();
+        assert!(matches!(
+            vm.eval("1 + true;"),
+            Err(InterpretError::Runtime(_))
+        ));
+        assert!(matches!(vm.eval("-nil;"), Err(InterpretError::Runtime(_))));
+        assert!(vm.stack.is_empty());
     }
 }
@@ -0,0 +1,155 @@
+use crate::object::{Heap, Map, Native, Obj};
+use crate::value::Value;
+
+pub const MAP_KEYS: &str = "Map keys must be strings, numbers, booleans or nil.";
+
+/// Functions defined as globals in every virtual machine.
+pub const NATIVES: [Native; 6] = [
+    Native {
+        name: "len",
+        arity: 1,
+        function: len,
+    },
+    Native {
+        name: "push",
+        arity: 2,
+        function: push,
+    },
+    Native {
+        name: "pop",
+        arity: 1,
+        function: pop,
+    },
+    Native {
+        name: "keys",
+        arity: 1,
+        function: keys,
+    },
+    Native {
+        name: "values",
+        arity: 1,
+        function: values,
+    },
+    Native {
+        name: "has",
+        arity: 2,
+        function: has,
+    },
+];
+
+/// Number of elements of an array or a map, or of characters of a string.
+fn len(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    let len = match args[0] {
+        Value::Obj(r) => match heap.get(r) {
+            Obj::Array(values) => Some(values.len()),
+            Obj::Map(map) => Some(map.len()),
+            Obj::String(s) => Some(s.chars().count()),
+            _ => None,
+        },
+        _ => None,
+    };
+    len.map(|len| Value::Number(len as f64))
+        .ok_or("len() expects an array, a map or a string.")
+}
+
+fn as_map(heap: &Heap, value: Value) -> Option<&Map> {
+    match value {
+        Value::Obj(r) => match heap.get(r) {
+            Obj::Map(map) => Some(map),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// New array of the keys of a map, in insertion order.
+fn keys(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    let map = as_map(heap, args[0]).ok_or("keys() expects a map.")?;
+    let keys = map.entries().iter().map(|&(key, _)| key).collect();
+    Ok(Value::Obj(heap.alloc(Obj::Array(keys))))
+}
+
+/// New array of the values of a map, in insertion order.
+fn values(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    let map = as_map(heap, args[0]).ok_or("values() expects a map.")?;
+    let values = map.entries().iter().map(|&(_, value)| value).collect();
+    Ok(Value::Obj(heap.alloc(Obj::Array(values))))
+}
+
+/// Whether the map has the key, its value may be nil.
+fn has(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    let map = as_map(heap, args[0]).ok_or("has() expects a map.")?;
+    let key = heap.map_key(args[1]).ok_or(MAP_KEYS)?;
+    Ok(Value::Bool(map.get(key).is_some()))
+}
+
+/// Append the second argument to the array.
+fn push(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    if let Value::Obj(r) = args[0] {
+        if let Obj::Array(values) = heap.get_mut(r) {
+            values.push(args[1]);
+            return Ok(Value::Nil);
+        }
+    }
+    Err("push() expects an array.")
+}
+
+/// Remove and return the last element of the array.
+fn pop(heap: &mut Heap, args: &[Value]) -> Result<Value, &'static str> {
+    if let Value::Obj(r) = args[0] {
+        if let Obj::Array(values) = heap.get_mut(r) {
+            return values.pop().ok_or("Can't pop from an empty array.");
+        }
+    }
+    Err("pop() expects an array.")
+}
+
+#[cfg(test)]
+mod test_native {
+    use super::*;
+
+    #[test]
+    fn test_array_natives() {
+        let mut heap = Heap::new();
+        let array = Value::Obj(heap.alloc(Obj::Array(vec![Value::Nil])));
+        assert_eq!(push(&mut heap, &[array, Value::Bool(true)]), Ok(Value::Nil));
+        assert_eq!(len(&mut heap, &[array]), Ok(Value::Number(2.)));
+        assert_eq!(pop(&mut heap, &[array]), Ok(Value::Bool(true)));
+        assert_eq!(pop(&mut heap, &[array]), Ok(Value::Nil));
+        assert!(pop(&mut heap, &[array]).is_err());
+        assert!(push(&mut heap, &[Value::Nil, Value::Nil]).is_err());
+    }
+
+    #[test]
+    fn test_map_natives() {
+        let mut heap = Heap::new();
+        let mut map = Map::default();
+        let k = Value::Obj(heap.intern("k"));
+        map.insert(heap.map_key(k).unwrap(), Value::Nil);
+        map.insert(heap.map_key(Value::Nil).unwrap(), Value::Number(1.));
+        let map = Value::Obj(heap.alloc(Obj::Map(map)));
+        assert_eq!(len(&mut heap, &[map]), Ok(Value::Number(2.)));
+        assert_eq!(has(&mut heap, &[map, k]), Ok(Value::Bool(true)));
+        assert_eq!(
+            has(&mut heap, &[map, Value::Bool(true)]),
+            Ok(Value::Bool(false))
+        );
+        assert!(has(&mut heap, &[map, map]).is_err());
+        let Ok(Value::Obj(keys)) = keys(&mut heap, &[map]) else {
+            panic!("Expected an array.");
+        };
+        assert_eq!(Value::Obj(keys).display(&heap).to_string(), "[k, nil]");
+        let Ok(Value::Obj(values)) = values(&mut heap, &[map]) else {
+            panic!("Expected an array.");
+        };
+        assert_eq!(Value::Obj(values).display(&heap).to_string(), "[nil, 1]");
+    }
+
+    #[test]
+    fn test_len_string() {
+        let mut heap = Heap::new();
+        let s = Value::Obj(heap.intern("héllo"));
+        assert_eq!(len(&mut heap, &[s]), Ok(Value::Number(5.)));
+        assert!(len(&mut heap, &[Value::Number(1.)]).is_err());
+    }
+}
@@ -0,0 +1,558 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::value::Value;
+use crate::{Chunk, Opcode};
+
+/// Bytes allocated before the first collection.
+const FIRST_GC: usize = 1024 * 1024;
+const GROWTH_FACTOR: usize = 2;
+
+/// Handle to an object living in the [Heap].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+/// Where a closure finds a captured variable when it is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    // A local slot of the enclosing function, or one of its upvalues.
+    pub local: bool,
+    pub index: u8,
+}
+
+/// A compiled function, the top level script is one without a name.
+#[derive(Debug)]
+pub struct Function {
+    pub arity: u8,
+    pub chunk: Chunk,
+    pub name: Option<Rc<str>>,
+    pub upvalues: Vec<Capture>,
+}
+
+impl Function {
+    pub fn new(name: Option<Rc<str>>) -> Function {
+        Function {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+/// A function with the variables it captured.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// Created by a struct declaration, its methods are keyed by interned names.
+#[derive(Debug)]
+pub struct Class {
+    pub name: Rc<str>,
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+impl Class {
+    pub fn new(name: Rc<str>) -> Class {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+/// An instance of a struct, its fields are keyed by interned names.
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// A method read from an instance, called with it as `self`.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// Signature of the functions implemented in Rust, the error is reported as a
+/// runtime error.
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, &'static str>;
+
+/// A function implemented in Rust.
+#[derive(Debug, Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+/// A value usable as a map key, see [Heap::map_key].
+#[derive(Debug, Clone, Copy)]
+pub struct MapKey(Value);
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &MapKey) -> bool {
+        match (self.0, other.0) {
+            // Unlike Lox equality, NaN finds itself.
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Value::Nil => 0u8.hash(state),
+            Value::Bool(b) => (1u8, b).hash(state),
+            Value::Number(n) => (2u8, n.to_bits()).hash(state),
+            // Strings are interned, the reference is the content.
+            Value::Obj(r) => (3u8, r).hash(state),
+        }
+    }
+}
+
+/// A hash map keeping the insertion order, which is the order of iteration.
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl Map {
+    pub fn get(&self, key: MapKey) -> Option<Value> {
+        self.index.get(&key).map(|&i| self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key.0, value));
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+}
+
+/// A captured variable, still on the stack until its scope ends.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// Objects too big or too dynamic to live directly in a [crate::value::Value].
+#[derive(Debug)]
+pub enum Obj {
+    String(Rc<str>),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+    Array(Vec<Value>),
+    Map(Map),
+}
+
+impl Obj {
+    /// Rough size of the object, including what it owns.
+    fn size(&self) -> usize {
+        size_of::<Obj>()
+            + match self {
+                Obj::String(s) => s.len(),
+                Obj::Function(function) => {
+                    let chunk = &function.chunk;
+                    chunk.code.len() * size_of::<Opcode>()
+                        + chunk.values.len() * size_of::<Value>()
+                        + chunk.lines.len() * size_of::<(u8, u32)>()
+                        + function.upvalues.len() * size_of::<Capture>()
+                }
+                Obj::Closure(closure) => closure.upvalues.len() * size_of::<ObjRef>(),
+                // Tables grow after the allocation, only their header is counted.
+                Obj::Upvalue(_)
+                | Obj::Class(_)
+                | Obj::Instance(_)
+                | Obj::BoundMethod(_)
+                | Obj::Native(_)
+                | Obj::Array(_)
+                | Obj::Map(_) => 0,
+            }
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::String(s) => write!(f, "{}", s),
+            Obj::Function(Function {
+                name: Some(name), ..
+            }) => write!(f, "<fn {}>", name),
+            Obj::Function(_) => write!(f, "<script>"),
+            // Printed as their function by ValueDisplay.
+            Obj::Closure(_) => write!(f, "<closure>"),
+            Obj::Upvalue(_) => write!(f, "<upvalue>"),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(_) => write!(f, "<instance>"),
+            Obj::BoundMethod(_) => write!(f, "<bound method>"),
+            Obj::Native(native) => write!(f, "<native fn {}>", native.name),
+            // Printed with their elements by ValueDisplay.
+            Obj::Array(_) => write!(f, "<array>"),
+            Obj::Map(_) => write!(f, "<map>"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    obj: Obj,
+    marked: bool,
+}
+
+/// Owner of every object allocated by the compiler or the virtual machine.
+///
+/// Strings are interned: two equal strings are always the same object, so
+/// comparing them is comparing their [ObjRef].
+///
+/// Memory is reclaimed by a mark and sweep collector. The heap does not know
+/// the roots: when [Heap::should_collect] says so, the virtual machine marks
+/// them with [Heap::mark_value] and calls [Heap::collect].
+pub struct Heap {
+    objects: Vec<Option<Slot>>,
+    // Slots freed by the last sweeps, reused before growing `objects`.
+    free: Vec<usize>,
+    // Marked objects whose references are not marked yet.
+    gray: Vec<ObjRef>,
+    // Share the allocation of the string object.
+    strings: HashMap<Rc<str>, ObjRef>,
+    intern_hits: usize,
+    intern_misses: usize,
+    bytes_allocated: usize,
+    next_gc: usize,
+    growth_factor: usize,
+    // Collect before every allocation.
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl fmt::Debug for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heap")
+            .field("objects", &(self.objects.len() - self.free.len()))
+            .field("interned", &self.strings.len())
+            .field("intern_hits", &self.intern_hits)
+            .field("intern_misses", &self.intern_misses)
+            .field("bytes_allocated", &self.bytes_allocated)
+            .field("next_gc", &self.next_gc)
+            .finish()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::with_capacity(64),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: HashMap::with_capacity(64),
+            intern_hits: 0,
+            intern_misses: 0,
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            growth_factor: GROWTH_FACTOR,
+            stress: false,
+        }
+    }
+
+    /// After a collection, the next one happens when the heap is `factor` times bigger.
+    pub fn set_growth_factor(&mut self, factor: usize) {
+        self.growth_factor = factor.max(1);
+    }
+
+    /// Collect on every allocation, to shake out rooting bugs.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+        let slot = Some(Slot { obj, marked: false });
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = slot;
+                ObjRef(idx)
+            }
+            None => {
+                self.objects.push(slot);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Get the string object for `s`, allocating it the first time.
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        if let Some(&r) = self.strings.get(s) {
+            self.intern_hits += 1;
+            return r;
+        }
+        self.intern_misses += 1;
+        let s: Rc<str> = s.into();
+        let r = self.alloc(Obj::String(s.clone()));
+        self.strings.insert(s, r);
+        r
+    }
+
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        &self.objects[r.0]
+            .as_ref()
+            .expect("Use of a collected object.")
+            .obj
+    }
+
+    pub fn as_str(&self, r: ObjRef) -> Option<&str> {
+        match self.get(r) {
+            Obj::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        &mut self.objects[r.0]
+            .as_mut()
+            .expect("Use of a collected object.")
+            .obj
+    }
+
+    pub fn as_function(&self, r: ObjRef) -> Option<&Function> {
+        match self.get(r) {
+            Obj::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self, r: ObjRef) -> Option<&Closure> {
+        match self.get(r) {
+            Obj::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self, r: ObjRef) -> Option<&Class> {
+        match self.get(r) {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self, r: ObjRef) -> Option<&Instance> {
+        match self.get(r) {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    /// Strings, numbers, booleans and nil can be used as map keys.
+    pub fn map_key(&self, value: Value) -> Option<MapKey> {
+        match value {
+            // 0 and -0 are equal, so they must be the same key.
+            Value::Number(0.) => Some(MapKey(Value::Number(0.))),
+            Value::Obj(r) => self.as_str(r).map(|_| MapKey(value)),
+            _ => Some(MapKey(value)),
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(r) = value {
+            self.mark_object(r);
+        }
+    }
+
+    pub fn mark_object(&mut self, r: ObjRef) {
+        let slot = self.objects[r.0]
+            .as_mut()
+            .expect("Marking a collected object.");
+        if !slot.marked {
+            slot.marked = true;
+            self.gray.push(r);
+        }
+    }
+
+    /// Free every object not reachable from the roots marked since the last collection.
+    pub fn collect(&mut self) {
+        while let Some(r) = self.gray.pop() {
+            self.blacken(r);
+        }
+        self.sweep();
+        self.next_gc = (self.bytes_allocated * self.growth_factor).max(FIRST_GC);
+    }
+
+    /// Mark the objects referenced by `r`.
+    fn blacken(&mut self, r: ObjRef) {
+        let references = match self.get(r) {
+            Obj::String(_) | Obj::Upvalue(Upvalue::Open(_)) | Obj::Native(_) => return,
+            Obj::Array(values) => values.clone(),
+            Obj::Map(map) => map
+                .entries()
+                .iter()
+                .flat_map(|&(key, value)| [key, value])
+                .collect(),
+            Obj::Function(function) => function.chunk.values.clone(),
+            Obj::Closure(closure) => {
+                let upvalues = closure.upvalues.iter().map(|&upvalue| Value::Obj(upvalue));
+                std::iter::once(Value::Obj(closure.function))
+                    .chain(upvalues)
+                    .collect()
+            }
+            Obj::Upvalue(Upvalue::Closed(value)) => vec![*value],
+            Obj::Class(class) => class
+                .methods
+                .iter()
+                .flat_map(|(&name, &method)| [Value::Obj(name), Value::Obj(method)])
+                .collect(),
+            Obj::Instance(instance) => std::iter::once(Value::Obj(instance.class))
+                .chain(
+                    instance
+                        .fields
+                        .iter()
+                        .flat_map(|(&name, &value)| [Value::Obj(name), value]),
+                )
+                .collect(),
+            Obj::BoundMethod(bound) => vec![bound.receiver, Value::Obj(bound.method)],
+        };
+        for value in references {
+            self.mark_value(value);
+        }
+    }
+
+    fn sweep(&mut self) {
+        // The intern table does not keep strings alive.
+        let objects = &self.objects;
+        self.strings
+            .retain(|_, r| objects[r.0].as_ref().is_some_and(|slot| slot.marked));
+        for (idx, entry) in self.objects.iter_mut().enumerate() {
+            match entry {
+                Some(slot) if slot.marked => slot.marked = false,
+                Some(slot) => {
+                    self.bytes_allocated -= slot.obj.size();
+                    *entry = None;
+                    self.free.push(idx);
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_object {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let mut heap = Heap::new();
+        let a = heap.intern("lox");
+        let b = heap.intern("lox");
+        let c = heap.intern("rlox");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(heap.objects.len(), 2);
+        assert_eq!((heap.intern_hits, heap.intern_misses), (1, 2));
+        assert!(format!("{:?}", heap)
+            .starts_with("Heap { objects: 2, interned: 2, intern_hits: 1, intern_misses: 2,"));
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        let lost = heap.intern("lost");
+        let before = heap.bytes_allocated;
+        heap.mark_object(kept);
+        heap.collect();
+        assert_eq!(heap.as_str(kept), Some("kept"));
+        assert!(heap.objects[lost.0].is_none());
+        assert!(heap.bytes_allocated < before);
+        // Not marked again, collected this time.
+        heap.collect();
+        assert!(heap.objects[kept.0].is_none());
+        // The intern table forgot them and their slots are reused.
+        let again = heap.intern("kept");
+        assert!(again == kept || again == lost);
+        assert_eq!(heap.intern_misses, 3);
+        assert_eq!(heap.objects.len(), 2);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut heap = Heap::new();
+        let mut map = Map::default();
+        let s = Value::Obj(heap.intern("k"));
+        map.insert(heap.map_key(s).unwrap(), Value::Nil);
+        map.insert(heap.map_key(Value::Number(-0.)).unwrap(), Value::Bool(true));
+        map.insert(heap.map_key(Value::Number(f64::NAN)).unwrap(), Value::Nil);
+        let again = Value::Obj(heap.intern("k"));
+        map.insert(heap.map_key(again).unwrap(), Value::Number(1.));
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(heap.map_key(s).unwrap()), Some(Value::Number(1.)));
+        assert_eq!(
+            map.get(heap.map_key(Value::Number(0.)).unwrap()),
+            Some(Value::Bool(true))
+        );
+        assert!(map
+            .get(heap.map_key(Value::Number(f64::NAN)).unwrap())
+            .is_some());
+        assert_eq!(map.get(heap.map_key(Value::Bool(false)).unwrap()), None);
+        assert_eq!(map.entries()[0].0, s);
+        let array = heap.alloc(Obj::Array(Vec::new()));
+        assert!(heap.map_key(Value::Obj(array)).is_none());
+    }
+
+    #[test]
+    fn test_collect_function_constants() {
+        let mut heap = Heap::new();
+        let mut function = Function::new(Some("f".into()));
+        let constant = heap.intern("constant");
+        function.chunk.write_value(Value::Obj(constant));
+        let function = heap.alloc(Obj::Function(function));
+        heap.mark_object(function);
+        heap.collect();
+        assert_eq!(heap.as_str(constant), Some("constant"));
+        assert_eq!(heap.get(function).to_string(), "<fn f>");
+    }
+}
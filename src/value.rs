@@ -0,0 +1,132 @@
+use core::fmt;
+
+use crate::object::{Heap, Obj, ObjRef};
+
+/// A Lox value, small enough to be copied around on the stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    /// `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Objects are only known by the heap, so printing a value needs it.
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay { value: self, heap }
+    }
+}
+
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_value(f, self.heap, self.value, &mut Vec::new())
+    }
+}
+
+/// Write `value`, `printing` holds the arrays and maps it is nested in so
+/// a container holding itself is written `[...]` or `{...}` instead of
+/// recursing forever.
+fn write_value(
+    f: &mut fmt::Formatter<'_>,
+    heap: &Heap,
+    value: Value,
+    printing: &mut Vec<ObjRef>,
+) -> fmt::Result {
+    match value {
+        Value::Nil => write!(f, "nil"),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::Number(n) => write!(f, "{}", n),
+        Value::Obj(r) => match heap.get(r) {
+            Obj::Closure(closure) => write!(f, "{}", heap.get(closure.function)),
+            Obj::BoundMethod(bound) => write_value(f, heap, Value::Obj(bound.method), printing),
+            Obj::Instance(instance) => write!(f, "{} instance", heap.get(instance.class)),
+            Obj::Array(_) if printing.contains(&r) => write!(f, "[...]"),
+            Obj::Array(values) => {
+                printing.push(r);
+                write!(f, "[")?;
+                for (i, &value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_value(f, heap, value, printing)?;
+                }
+                printing.pop();
+                write!(f, "]")
+            }
+            Obj::Map(_) if printing.contains(&r) => write!(f, "{{...}}"),
+            Obj::Map(map) => {
+                printing.push(r);
+                write!(f, "{{")?;
+                for (i, &(key, value)) in map.entries().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_value(f, heap, key, printing)?;
+                    write!(f, ": ")?;
+                    write_value(f, heap, value, printing)?;
+                }
+                printing.pop();
+                write!(f, "}}")
+            }
+            obj => write!(f, "{}", obj),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_value {
+    use super::*;
+
+    #[test]
+    fn test_falsey() {
+        assert!(Value::Nil.is_falsey());
+        assert!(Value::Bool(false).is_falsey());
+        assert!(!Value::Bool(true).is_falsey());
+        assert!(!Value::Number(0.).is_falsey());
+    }
+
+    #[test]
+    fn test_equality() {
+        assert_eq!(Value::Nil, Value::Nil);
+        assert_eq!(Value::Number(1.), Value::Number(1.));
+        assert_ne!(Value::Number(0.), Value::Bool(false));
+        assert_ne!(Value::Nil, Value::Bool(false));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut heap = Heap::new();
+        let s = Value::Obj(heap.intern("rlox"));
+        assert_eq!(Value::Nil.display(&heap).to_string(), "nil");
+        assert_eq!(Value::Bool(true).display(&heap).to_string(), "true");
+        assert_eq!(Value::Number(3.).display(&heap).to_string(), "3");
+        assert_eq!(Value::Number(-1.5).display(&heap).to_string(), "-1.5");
+        assert_eq!(s.display(&heap).to_string(), "rlox");
+    }
+
+    #[test]
+    fn test_display_cycles() {
+        let mut heap = Heap::new();
+        let inner = heap.alloc(Obj::Array(vec![Value::Nil]));
+        let outer = heap.alloc(Obj::Array(vec![Value::Obj(inner), Value::Obj(inner)]));
+        if let Obj::Array(values) = heap.get_mut(inner) {
+            values.push(Value::Obj(outer));
+        }
+        // Only the arrays being printed are cut, not the ones printed before.
+        assert_eq!(
+            Value::Obj(outer).display(&heap).to_string(),
+            "[[nil, [...]], [nil, [...]]]"
+        );
+    }
+}